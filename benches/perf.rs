@@ -0,0 +1,229 @@
+//! Benchmarks of the interpreter, run through the library API with
+//! `cargo bench`.
+//!
+//! Each benchmark is run for `MAL_BENCH_SECS` seconds (1 by default) and
+//! reports iterations per second, once per engine where the engine
+//! matters. The results are saved in `target/mal-bench.tsv`, and each run
+//! shows the change since the previous one. Pass a name to only run the
+//! benchmarks whose names contain it, eg. `cargo bench -- fib`.
+//!
+//! The perf files load `core.mal`, where `cond` and `or` are functions
+//! rather than the macros they are upstream, so they evaluate all of their
+//! arguments: perf1 to perf3 don't measure the same code as upstream mal,
+//! and their numbers can't be compared with its.
+extern crate mal;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use mal::{Interpreter, Engine, Mal, MalList, Result};
+use mal::reader;
+
+const ENGINES: [Engine; 2] = [Engine::Tree, Engine::Vm];
+
+struct Bench {
+    name: &'static str,
+    workload: Workload,
+}
+
+enum Workload {
+    /// The setup forms are evaluated once, and the body in each iteration.
+    Code { setup: &'static str, body: &'static str },
+    /// A file of `maltests`, whose timed form is the body.
+    PerfFile(&'static str),
+}
+
+const BENCHES: &[Bench] = &[
+    Bench { name: "perf1", workload: Workload::PerfFile("perf1.mal") },
+    Bench { name: "perf2", workload: Workload::PerfFile("perf2.mal") },
+    Bench { name: "perf3", workload: Workload::PerfFile("perf3.mal") },
+    Bench {
+        name: "fib",
+        workload: Workload::Code {
+            setup: "(def! fib (fn* [n] (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))",
+            body: "(fib 18)",
+        },
+    },
+    Bench {
+        name: "loop",
+        workload: Workload::Code {
+            setup: "(def! count-to (fn* [n] (loop [i 0] (if (< i n) (recur (+ i 1)) i))))",
+            body: "(count-to 10000)",
+        },
+    },
+    Bench {
+        name: "map-build",
+        workload: Workload::Code {
+            setup: "(def! build (fn* [n] \
+            (loop [i 0 m {}] (if (< i n) (recur (+ i 1) (assoc m i (* i i))) m))))",
+            body: "(count (build 1000))",
+        },
+    },
+    Bench {
+        name: "map-lookup",
+        workload: Workload::Code {
+            setup: "(def! m (loop [i 0 m {}] (if (< i 1000) (recur (+ i 1) (assoc m (str i) i)) m))) \
+            (def! sum-keys (fn* [m] \
+                (loop [ks (keys m) acc 0] (if (empty? ks) acc (recur (rest ks) (+ acc (get m (first ks))))))))",
+            body: "(sum-keys m)",
+        },
+    },
+    Bench {
+        name: "map-destructure",
+        workload: Workload::Code {
+            setup: "(def! point (fn* [{:keys [x y] :or {y 0}}] (+ x y))) \
+            (def! run (fn* [n] (loop [i 0 acc 0] (if (< i n) (recur (+ i 1) (+ acc (point {:x i}))) acc))))",
+            body: "(run 1000)",
+        },
+    },
+];
+
+const PERF_FILE_NOTE: &str = "note: cond and or are functions in core.mal, not macros, \
+    so perf1-3 evaluate every branch and aren't comparable with upstream mal";
+
+/// Code to read in the reader benchmark, about 1MB of it.
+fn reader_text() -> String {
+    let mut text = String::new();
+    for i in 0..10_000 {
+        writeln!(text, "(def! item-{} {{:id {} :tags #{{:a :b}} :name \"item \\\"{}\\\"\" \
+            :values [1 2.5 -3 (+ {} 1)] :f (fn* [x & more] (list x more))}}) ; comment",
+            i, i, i, i).unwrap();
+    }
+    text
+}
+
+fn main() {
+    // The perf files load "../core.mal" and "../perf.mal".
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    env::set_current_dir(root.join("maltests")).expect("Could not find maltests");
+    let secs = match env::var("MAL_BENCH_SECS") {
+        Ok(secs) => secs.parse().expect("Invalid MAL_BENCH_SECS"),
+        Err(_) => 1.0,
+    };
+    let budget = Duration::from_secs_f64(secs);
+    // Cargo passes `--bench`, and the filter if there is one.
+    let filter = env::args().skip(1).find(|arg| ! arg.starts_with('-'));
+    let selected = |name: &str| filter.as_ref().is_none_or(|filter| name.contains(filter.as_str()));
+
+    let results_path = root.join("target").join("mal-bench.tsv");
+    let previous = read_results(&results_path);
+    let mut results = Vec::new();
+    let mut record = |name: String, rate: f64| {
+        match previous.get(&name) {
+            Some(old) => println!("{:<28} {:>12.1} iters/s  {:>+6.1}%", name, rate, (rate / old - 1.0) * 100.0),
+            None => println!("{:<28} {:>12.1} iters/s", name, rate),
+        }
+        results.push((name, rate));
+    };
+
+    let perf_files = BENCHES.iter()
+        .any(|bench| selected(bench.name) && matches!(bench.workload, Workload::PerfFile(_)));
+    if perf_files {
+        println!("{}", PERF_FILE_NOTE);
+    }
+    for bench in BENCHES.iter().filter(|bench| selected(bench.name)) {
+        for &engine in &ENGINES {
+            let name = format!("{}/{}", bench.name, engine);
+            match run_bench(bench, engine, budget) {
+                Ok(rate) => record(name, rate),
+                Err(err) => println!("{:<28} failed: {}", name, err),
+            }
+        }
+    }
+    if selected("reader") {
+        let text = reader_text();
+        let rate = measure(budget, || reader::read_all(&text).map(|_| ())).unwrap();
+        record(format!("reader/{}kB", text.len() / 1024), rate);
+    }
+
+    if filter.is_none() {
+        write_results(&results_path, &results);
+    }
+}
+
+fn run_bench(bench: &Bench, engine: Engine, budget: Duration) -> Result<f64> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_engine(engine);
+    let body = match bench.workload {
+        Workload::Code { setup, body } => {
+            for form in reader::read_all(setup)? {
+                interpreter.eval(&form)?;
+            }
+            reader::read_str(body)?
+        }
+        Workload::PerfFile(path) => load_perf_file(path, &mut interpreter)?,
+    };
+    measure(budget, || interpreter.eval(&body).map(|_| ()))
+}
+
+/// Evaluates a perf file, except for the form that measures its workload,
+/// and returns the workload instead: the expression given to `time`, or a
+/// call of the function given to `run-fn-for`.
+fn load_perf_file(path: &str, interpreter: &mut Interpreter) -> Result<Mal> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Could not read '{}': {}", path, err))?;
+    let mut body = None;
+    for form in reader::read_all(&text)? {
+        match workload(&form) {
+            Some(workload) => body = Some(workload),
+            None => {
+                interpreter.eval(&form)?;
+            }
+        }
+    }
+    body.ok_or_else(|| format!("'{}' has no time or run-fn-for form", path).into())
+}
+
+fn workload(form: &Mal) -> Option<Mal> {
+    let list = match *form {
+        Mal::List(ref list) if ! list.is_empty() => list,
+        _ => return None,
+    };
+    match list[0] {
+        Mal::Sym(ref sym) if sym.text() == "time" => list.get(1).cloned(),
+        Mal::Sym(ref sym) if sym.text() == "run-fn-for" => {
+            list.get(1).map(|func| list_of(vec![func.clone()]))
+        }
+        _ => list.iter().filter_map(workload).next(),
+    }
+}
+
+fn list_of(items: Vec<Mal>) -> Mal {
+    items.into_iter().collect::<MalList>().into()
+}
+
+/// Runs the function for about the given time, after a warm-up run, and
+/// returns how many times it ran per second.
+fn measure<F>(budget: Duration, mut func: F) -> Result<f64> where F: FnMut() -> Result<()> {
+    func()?;
+    let start = Instant::now();
+    let mut iterations = 0u64;
+    while start.elapsed() < budget {
+        func()?;
+        iterations += 1;
+    }
+    Ok(iterations as f64 / start.elapsed().as_secs_f64())
+}
+
+fn read_results(path: &Path) -> HashMap<String, f64> {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    text.lines().filter_map(|line| {
+        let mut fields = line.split('\t');
+        let name = fields.next()?;
+        let rate = fields.next()?.parse().ok()?;
+        Some((name.to_string(), rate))
+    }).collect()
+}
+
+fn write_results(path: &Path, results: &[(String, f64)]) {
+    let mut text = String::new();
+    for (name, rate) in results {
+        writeln!(text, "{}\t{}", name, rate).unwrap();
+    }
+    if let Err(err) = fs::write(path, text) {
+        eprintln!("Could not save the results to '{}': {}", path.display(), err);
+    }
+}
@@ -0,0 +1,931 @@
+//! Turns read forms into the nodes of `ast`, before they are evaluated.
+//!
+//! Special forms are checked once here instead of on every evaluation,
+//! locals are given slots in the frame of their function, and the locals a
+//! closure uses from enclosing functions become its upvalues. Globals are
+//! resolved to their vars, which may be defined later.
+use std::rc::Rc;
+use im_rc::Vector;
+use types::{Mal, MalList, MalArr, MalMap, Arity, Symbol, Keyword};
+use ast::{Node, Lambda, LambdaArity, Body, Capture, Pattern, MapEntry, MatchPattern,
+    MatchClause, RequireSpec};
+use env::{Env, Var};
+use printer;
+use errors::*;
+
+/// Analyzes a top-level form. Its locals need a frame of the returned size.
+pub fn analyze(form: &Mal, env: &Env) -> Result<(Node, usize)> {
+    let mut analyzer = Analyzer { env, scopes: vec![FnScope::default()] };
+    let ctx = Ctx { tail: true, recur_tail: false, recur: None };
+    let node = analyzer.form(form, ctx)?;
+    Ok((node, analyzer.scopes[0].nslots))
+}
+
+/// The locals of a function being analyzed.
+#[derive(Default)]
+struct FnScope {
+    /// Locals in scope, innermost last.
+    locals: Vec<(Symbol, usize)>,
+    nslots: usize,
+    captures: Vec<Capture>,
+    upvalue_names: Vec<Symbol>,
+    /// The name that refers to the function itself.
+    self_name: Option<Symbol>,
+}
+
+/// What `recur` jumps back to, with the number of values it takes and
+/// whether it takes more.
+#[derive(Clone, Copy)]
+enum RecurTarget {
+    Loop(usize),
+    Fn(usize, bool),
+}
+
+/// Where a form is, as far as calls and `recur` care.
+#[derive(Clone, Copy)]
+struct Ctx {
+    /// In tail position of a function, so calls can be tail calls.
+    tail: bool,
+    /// In tail position of the innermost loop or function body.
+    recur_tail: bool,
+    recur: Option<RecurTarget>,
+}
+
+impl Ctx {
+    fn non_tail(self) -> Ctx {
+        Ctx { tail: false, recur_tail: false, recur: self.recur }
+    }
+}
+
+fn pattern_str(pattern: &Mal) -> String {
+    printer::pr_str(pattern, true).unwrap_or_else(|_| pattern.type_name().into())
+}
+
+fn is_sym(value: &Mal, name: &str) -> bool {
+    match *value {
+        Mal::Sym(ref sym) => sym.text() == name,
+        _ => false,
+    }
+}
+
+fn is_kw(value: &Mal, name: &str) -> bool {
+    match *value {
+        Mal::Kw(ref kw) => kw.symbol() == name,
+        _ => false,
+    }
+}
+
+fn as_symbol<'a>(value: &'a Mal, pattern: &Mal) -> Result<&'a Symbol> {
+    match *value {
+        Mal::Sym(ref sym) => Ok(sym),
+        ref other => bail!("Expected a symbol in pattern {}, found {}",
+            pattern_str(pattern), other.type_name()),
+    }
+}
+
+fn assert_arg_len(name: &str, nargs: usize, args: &[Mal]) -> Result<()> {
+    if args.len() != nargs {
+        bail!("'{}' takes {} arguments, found {}", name, nargs, args.len());
+    }
+    Ok(())
+}
+
+/// Returns the items of a binding vector, which must come in pairs.
+fn bindings<'a>(name: &str, args: &'a [Mal]) -> Result<&'a Vector<Mal>> {
+    if args.is_empty() {
+        bail!("'{}' takes 1 or more arguments, found 0", name);
+    }
+    let bindings = match args[0] {
+        Mal::List(ref list) => &*list.items,
+        Mal::Arr(ref arr) => &*arr.items,
+        ref other => return Err(Error::from(ErrorKind::TypeError {
+            expected: "array or list".into(),
+            got: other.type_name().into(),
+        })).chain_err(|| format!("{}: Invalid set of bindings", name)),
+    };
+    if (bindings.len() % 2) != 0 {
+        bail!("{}: odd number of elements in binding list", name);
+    }
+    Ok(bindings)
+}
+
+/// Turns a body of any number of expressions into a single form.
+fn body_form(exprs: &[Mal]) -> Mal {
+    if exprs.len() == 1 {
+        exprs[0].clone()
+    } else {
+        let mut list: MalList = exprs.iter().cloned().collect();
+        list.push_front(Symbol::new("do").into());
+        list.into()
+    }
+}
+
+/// Checks for the `(fn* ([x] ...) ([x y] ...))` form, where every argument
+/// is a parameter vector followed by a body. The parameters of a single
+/// arity may be a list, so `(fn* ([a b]) ((f) a b))` has one arity that
+/// destructures its argument.
+fn is_multi_arity(args: &[Mal]) -> bool {
+    ! args.is_empty() && args.iter().all(|clause| {
+        match *clause {
+            Mal::List(ref clause) => matches!(clause.front(), Some(&Mal::Arr(_))),
+            _ => false,
+        }
+    })
+}
+
+/// Makes sure the arity to call is never ambiguous.
+fn check_arities(arities: &[Arity]) -> Result<()> {
+    let mut variadic = None;
+    for (i, arity) in arities.iter().enumerate() {
+        let (nargs, more) = arity.required();
+        if more {
+            if variadic.is_some() {
+                bail!("fn*: Only one arity can take a variable number of arguments");
+            }
+            variadic = Some(nargs);
+        } else if arities[..i].iter().any(|other| other.required() == (nargs, false)) {
+            bail!("fn*: More than one arity takes {} arguments", nargs);
+        }
+    }
+    if let Some(variadic) = variadic {
+        if arities.iter().any(|arity| {
+            let (nargs, more) = arity.required();
+            ! more && nargs > variadic
+        }) {
+            bail!("fn*: Fixed arities can't take more arguments than the variadic one");
+        }
+    }
+    Ok(())
+}
+
+/// Splits the clauses of a `match`, written as `pattern body` or
+/// `pattern :when guard body`.
+fn match_clauses(forms: &[Mal]) -> Result<Vec<(&Mal, Option<&Mal>, &Mal)>> {
+    let mut clauses = Vec::new();
+    let mut forms = forms.iter();
+    while let Some(pattern) = forms.next() {
+        let mut body = forms.next();
+        let mut guard = None;
+        if body.is_some_and(|body| is_kw(body, "when")) {
+            guard = forms.next();
+            body = forms.next();
+        }
+        match body {
+            Some(body) => clauses.push((pattern, guard, body)),
+            None => bail!("match: Clause for pattern {} has no body", pattern_str(pattern)),
+        }
+    }
+    Ok(clauses)
+}
+
+struct Analyzer<'a> {
+    env: &'a Env,
+    /// The function being analyzed, innermost last. The first is the
+    /// top-level form.
+    scopes: Vec<FnScope>,
+}
+
+impl<'a> Analyzer<'a> {
+    fn scope(&mut self) -> &mut FnScope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    /// Gives the symbol a new slot in the current function.
+    fn add_local(&mut self, sym: &Symbol) -> usize {
+        let scope = self.scope();
+        let slot = scope.nslots;
+        scope.nslots += 1;
+        scope.locals.push((sym.clone(), slot));
+        slot
+    }
+
+    /// Whether `def!` defines a global here, which it does outside of any
+    /// function or local scope.
+    fn at_top_level(&self) -> bool {
+        self.scopes.len() == 1 && self.scopes[0].locals.is_empty()
+    }
+
+    /// Finds a local of the function at the given level, capturing it
+    /// from the enclosing functions if needed.
+    fn resolve_local(&mut self, level: usize, sym: &Symbol) -> Option<Capture> {
+        {
+            let scope = &self.scopes[level];
+            if let Some(&(_, slot)) = scope.locals.iter().rev().find(|local| local.0 == *sym) {
+                return Some(Capture::Local(slot));
+            }
+            if scope.self_name.as_ref() == Some(sym) {
+                return Some(Capture::SelfRef);
+            }
+            if let Some(i) = scope.upvalue_names.iter().position(|name| name == sym) {
+                return Some(Capture::Upvalue(i));
+            }
+        }
+        if level == 0 {
+            return None;
+        }
+        let outer = self.resolve_local(level - 1, sym)?;
+        let scope = &mut self.scopes[level];
+        scope.captures.push(outer);
+        scope.upvalue_names.push(sym.clone());
+        Some(Capture::Upvalue(scope.captures.len() - 1))
+    }
+
+    fn symbol(&mut self, sym: &Symbol) -> Node {
+        let level = self.scopes.len() - 1;
+        match self.resolve_local(level, sym) {
+            Some(Capture::Local(slot)) => Node::Local(slot),
+            Some(Capture::Upvalue(i)) => Node::Upvalue(i),
+            Some(Capture::SelfRef) => Node::SelfRef,
+            None => Node::Global(self.env.resolve_or_add(sym), sym.clone()),
+        }
+    }
+
+    fn form(&mut self, form: &Mal, ctx: Ctx) -> Result<Node> {
+        match *form {
+            Mal::Sym(ref sym) => Ok(self.symbol(sym)),
+            Mal::List(ref list) if ! list.is_empty() => {
+                let items: Vec<Mal> = list.iter().cloned().collect();
+                self.list(&items, ctx)
+            }
+            Mal::Arr(ref arr) => {
+                let nodes = self.forms(arr.iter(), ctx)?;
+                if nodes.iter().all(is_const) {
+                    return Ok(Node::Const(form.clone()));
+                }
+                Ok(Node::Vector(nodes))
+            }
+            Mal::Map(ref map) => {
+                let mut entries = Vec::new();
+                for (key, value) in map {
+                    entries.push((self.form(key, ctx.non_tail())?, self.form(value, ctx.non_tail())?));
+                }
+                if entries.iter().all(|(key, value)| is_const(key) && is_const(value)) {
+                    return Ok(Node::Const(form.clone()));
+                }
+                Ok(Node::Map(entries))
+            }
+            Mal::Set(ref set) => {
+                let nodes = self.forms(set.iter(), ctx)?;
+                if nodes.iter().all(is_const) {
+                    return Ok(Node::Const(form.clone()));
+                }
+                Ok(Node::Set(nodes))
+            }
+            // Everything else, including the empty list, evaluates to itself.
+            ref other => Ok(Node::Const(other.clone())),
+        }
+    }
+
+    fn forms<'b, I>(&mut self, forms: I, ctx: Ctx) -> Result<Vec<Node>>
+            where I: Iterator<Item=&'b Mal> {
+        forms.map(|form| self.form(form, ctx.non_tail())).collect()
+    }
+
+    /// Analyzes a body of any number of forms, the last in tail position.
+    fn body(&mut self, forms: &[Mal], ctx: Ctx) -> Result<Node> {
+        match forms.len() {
+            0 => Ok(Node::Const(Mal::Nil)),
+            1 => self.form(&forms[0], ctx),
+            len => {
+                let mut nodes = Vec::with_capacity(len);
+                for (i, form) in forms.iter().enumerate() {
+                    let ctx = if i + 1 == len { ctx } else { ctx.non_tail() };
+                    nodes.push(self.form(form, ctx)?);
+                }
+                Ok(Node::Do(nodes))
+            }
+        }
+    }
+
+    fn list(&mut self, items: &[Mal], ctx: Ctx) -> Result<Node> {
+        let args = &items[1..];
+        let name = match items[0] {
+            Mal::Sym(ref sym) => sym.text(),
+            _ => "",
+        };
+        match name {
+            "def!" => self.def(args, ctx),
+            "let*" => {
+                let bindings = bindings("let*", args)?;
+                let mark = self.scope().locals.len();
+                let mut nodes = Vec::new();
+                for pair in bindings.iter().collect::<Vec<_>>().chunks(2) {
+                    let value = self.form(pair[1], ctx.non_tail())?;
+                    let pattern = self.pattern(pair[0], ctx)
+                        .chain_err(|| "let*: Invalid binding variable")?;
+                    nodes.push((pattern, value));
+                }
+                let body = self.body(&args[1..], ctx)?;
+                self.scope().locals.truncate(mark);
+                Ok(Node::Let(nodes, Box::new(body)))
+            }
+            "loop" => {
+                let bindings = bindings("loop", args)?;
+                let mark = self.scope().locals.len();
+                let mut nodes = Vec::new();
+                for pair in bindings.iter().collect::<Vec<_>>().chunks(2) {
+                    let value = self.form(pair[1], ctx.non_tail())?;
+                    let pattern = self.pattern(pair[0], ctx)
+                        .chain_err(|| "loop: Invalid binding variable")?;
+                    nodes.push((pattern, value));
+                }
+                let body_ctx = Ctx {
+                    tail: ctx.tail,
+                    recur_tail: true,
+                    recur: Some(RecurTarget::Loop(nodes.len())),
+                };
+                let body = self.body(&args[1..], body_ctx)?;
+                self.scope().locals.truncate(mark);
+                Ok(Node::Loop(nodes, Box::new(body)))
+            }
+            "recur" => {
+                let target = match ctx.recur {
+                    Some(target) => target,
+                    None => bail!("recur: Not inside a loop or fn*"),
+                };
+                if ! ctx.recur_tail {
+                    bail!("recur: Can only be used in tail position");
+                }
+                match target {
+                    RecurTarget::Loop(nargs) if args.len() != nargs => {
+                        bail!("recur: Expected {} arguments, found {}", nargs, args.len());
+                    }
+                    RecurTarget::Fn(nargs, false) if args.len() != nargs => {
+                        bail!("recur: Expected {} arguments, found {}", nargs, args.len());
+                    }
+                    RecurTarget::Fn(nargs, true) if args.len() < nargs => {
+                        bail!("recur: Expected {} or more arguments, found {}", nargs, args.len());
+                    }
+                    _ => {}
+                }
+                Ok(Node::Recur(self.forms(args.iter(), ctx)?))
+            }
+            "fn*" => Ok(Node::Fn(Rc::new(self.lambda(None, None, args)?))),
+            "do" => self.body(args, ctx),
+            "if" => {
+                if ! (args.len() == 2 || args.len() == 3) {
+                    bail!("'if' takes 2 or 3 arguments, found {}", args.len());
+                }
+                let condition = self.form(&args[0], ctx.non_tail())?;
+                let then = self.form(&args[1], ctx)?;
+                let otherwise = match args.get(2) {
+                    Some(form) => Some(Box::new(self.form(form, ctx)?)),
+                    None => None,
+                };
+                Ok(Node::If(Box::new(condition), Box::new(then), otherwise))
+            }
+            "lazy-seq" => {
+                // The body becomes a function without arguments, run when
+                // the sequence is first used.
+                self.scopes.push(FnScope::default());
+                let body_ctx = Ctx { tail: true, recur_tail: false, recur: None };
+                let body = self.body(args, body_ctx);
+                let scope = self.scopes.pop().unwrap();
+                let arity = LambdaArity { params: Vec::new(), rest: None, body: Body::Tree(body?) };
+                let source = Arity { params: MalArr::new(), body: body_form(args) };
+                Ok(Node::LazySeq(Rc::new(Lambda {
+                    name: None,
+                    arities: vec![arity],
+                    source: vec![source],
+                    captures: scope.captures,
+                    nslots: scope.nslots,
+                })))
+            }
+            "match" => {
+                if args.is_empty() {
+                    bail!("'match' takes 1 or more arguments, found 0");
+                }
+                let value = self.form(&args[0], ctx.non_tail())?;
+                let mut clauses = Vec::new();
+                for (pattern, guard, body) in match_clauses(&args[1..])? {
+                    let mark = self.scope().locals.len();
+                    let pattern = self.match_pattern(pattern)
+                        .chain_err(|| "match: Invalid pattern")?;
+                    let guard = match guard {
+                        Some(guard) => Some(self.form(guard, ctx.non_tail())?),
+                        None => None,
+                    };
+                    let body = self.form(body, ctx)?;
+                    self.scope().locals.truncate(mark);
+                    clauses.push(MatchClause { pattern, guard, body });
+                }
+                Ok(Node::Match(Box::new(value), clauses))
+            }
+            "ns" => {
+                if args.is_empty() {
+                    bail!("'ns' takes 1 or more arguments, found 0");
+                }
+                let ns = args[0].clone().symbol()
+                    .chain_err(|| "ns: Invalid namespace name")?;
+                let mut specs = Vec::new();
+                for clause in &args[1..] {
+                    let mut clause = clause.clone().list().chain_err(|| "ns: Invalid clause")?;
+                    if clause.pop_front() != Some(Keyword::new("require").into()) {
+                        bail!("ns: Only (:require ...) clauses are supported");
+                    }
+                    for spec in clause.iter() {
+                        specs.push(require_spec(spec)?);
+                    }
+                }
+                Ok(Node::Ns(self.env.clone(), ns, specs))
+            }
+            "in-ns" => {
+                assert_arg_len("in-ns", 1, args)?;
+                let ns = args[0].clone().symbol()
+                    .chain_err(|| "in-ns: Invalid namespace name")?;
+                Ok(Node::InNs(self.env.clone(), ns))
+            }
+            "require" => {
+                let specs = args.iter().map(require_spec).collect::<Result<_>>()?;
+                Ok(Node::Require(self.env.clone(), specs))
+            }
+            "load-file" => {
+                assert_arg_len("load-file", 1, args)?;
+                let path = self.form(&args[0], ctx.non_tail())?;
+                Ok(Node::LoadFile(self.env.clone(), Box::new(path)))
+            }
+            "time" => {
+                assert_arg_len("time", 1, args)?;
+                self.form(&time_form(&args[0]), ctx)
+            }
+            _ => {
+                let func = self.form(&items[0], ctx.non_tail())?;
+                let args = self.forms(args.iter(), ctx)?;
+                Ok(Node::Call { func: Box::new(func), args, tail: ctx.tail })
+            }
+        }
+    }
+
+    fn def(&mut self, args: &[Mal], ctx: Ctx) -> Result<Node> {
+        assert_arg_len("def!", 2, args)?;
+        let (target, value) = (&args[0], &args[1]);
+        match *target {
+            Mal::Sym(_) | Mal::Arr(_) | Mal::Map(_) => {}
+            ref other => {
+                return Err(Error::from(format!("Invalid binding pattern: {}", pattern_str(other))))
+                    .chain_err(|| "def!: Invalid first argument");
+            }
+        }
+        if self.at_top_level() {
+            if let Mal::Sym(ref sym) = *target {
+                // The var comes first, so a function can call itself through it.
+                let var = self.env.define_var(sym);
+                let value = self.named_value(sym, false, value, ctx)?;
+                return Ok(Node::Def(self.env.clone(), var, Box::new(value)));
+            }
+            let value = self.form(value, ctx.non_tail())?;
+            // The pattern binds slots, which are then copied to the globals.
+            let mark = self.scope().locals.len();
+            let pattern = self.pattern(target, ctx)
+                .chain_err(|| "def!: Invalid first argument")?;
+            let locals = self.scope().locals.split_off(mark);
+            let vars: Vec<(Var, usize)> = locals.into_iter()
+                .map(|(sym, slot)| (self.env.define_var(&sym), slot))
+                .collect();
+            return Ok(Node::DefPattern(self.env.clone(), pattern, vars, Box::new(value)));
+        }
+        let value = match *target {
+            Mal::Sym(ref sym) => self.named_value(sym, true, value, ctx)?,
+            _ => self.form(value, ctx.non_tail())?,
+        };
+        let pattern = self.pattern(target, ctx)
+            .chain_err(|| "def!: Invalid first argument")?;
+        Ok(Node::DefLocal(pattern, Box::new(value)))
+    }
+
+    /// Analyzes the value of a `def!`, naming it if it's a function.
+    fn named_value(&mut self, name: &Symbol, local: bool, value: &Mal, ctx: Ctx) -> Result<Node> {
+        if let Mal::List(ref list) = *value {
+            if list.front().is_some_and(|head| is_sym(head, "fn*")) {
+                let args: Vec<Mal> = list.iter().skip(1).cloned().collect();
+                // A local can't be referred to before it is bound, so the
+                // function refers to itself directly.
+                let self_name = if local { Some(name.clone()) } else { None };
+                let lambda = self.lambda(Some(name.clone()), self_name, &args)?;
+                return Ok(Node::Fn(Rc::new(lambda)));
+            }
+        }
+        self.form(value, ctx.non_tail())
+    }
+
+    fn lambda(&mut self, name: Option<Symbol>, self_name: Option<Symbol>,
+            args: &[Mal]) -> Result<Lambda> {
+        let clauses: Vec<(Mal, Vec<Mal>)> = if is_multi_arity(args) {
+            args.iter().map(|clause| {
+                let items: Vec<Mal> = clause.clone().list().unwrap().iter().cloned().collect();
+                (items[0].clone(), items[1..].to_vec())
+            }).collect()
+        } else {
+            if args.is_empty() {
+                bail!("'fn*' takes 1 or more arguments, found 0");
+            }
+            vec![(args[0].clone(), args[1..].to_vec())]
+        };
+        let mut source = Vec::new();
+        for (params, body) in &clauses {
+            let params: MalArr = match *params {
+                Mal::List(ref list) => list.items.as_ref().clone().into(),
+                Mal::Arr(ref arr) => arr.clone(),
+                ref other => {
+                    return Err(Error::from(ErrorKind::TypeError {
+                        expected: "array or list".into(),
+                        got: other.type_name().into(),
+                    })).chain_err(|| "fn*: Invalid argument list");
+                }
+            };
+            source.push(Arity { params, body: body_form(body) });
+        }
+        check_arities(&source)?;
+
+        self.scopes.push(FnScope { self_name, ..FnScope::default() });
+        let arities = source.iter().zip(&clauses)
+            .map(|(arity, (_, body))| self.arity(arity, body))
+            .collect::<Result<Vec<_>>>();
+        let scope = self.scopes.pop().unwrap();
+        Ok(Lambda {
+            name,
+            arities: arities?,
+            source,
+            captures: scope.captures,
+            nslots: scope.nslots,
+        })
+    }
+
+    fn arity(&mut self, arity: &Arity, body: &[Mal]) -> Result<LambdaArity> {
+        self.scope().locals.clear();
+        let pattern: Mal = arity.params.clone().into();
+        if arity.params.iter().any(|param| is_kw(param, "as")) {
+            bail!("fn*: :as is not allowed in an argument list");
+        }
+        let ctx = Ctx { tail: true, recur_tail: false, recur: None };
+        let (params, rest, _) = self.seq_pattern(&arity.params, &pattern, ctx)
+            .chain_err(|| "fn*: Invalid argument list")?;
+        let (nargs, more) = arity.required();
+        let ctx = Ctx { tail: true, recur_tail: true, recur: Some(RecurTarget::Fn(nargs, more)) };
+        let body = self.body(body, ctx)?;
+        Ok(LambdaArity { params, rest: rest.map(|rest| *rest), body: Body::Tree(body) })
+    }
+
+    /// Analyzes a destructuring pattern, adding the locals it binds.
+    fn pattern(&mut self, form: &Mal, ctx: Ctx) -> Result<Pattern> {
+        match *form {
+            Mal::Sym(ref sym) => Ok(Pattern::Slot(self.add_local(sym))),
+            Mal::Arr(ref items) => {
+                let (items, rest, whole) = self.seq_pattern(items, form, ctx)?;
+                Ok(Pattern::Seq { items, rest, whole, source: form.clone() })
+            }
+            Mal::Map(ref map) => self.map_pattern(map, form, ctx),
+            ref other => bail!("Invalid binding pattern: {}", pattern_str(other)),
+        }
+    }
+
+    /// Analyzes the items of a sequential pattern, also used for parameter lists.
+    #[allow(clippy::type_complexity)]
+    fn seq_pattern(&mut self, items: &Vector<Mal>, pattern: &Mal, ctx: Ctx)
+            -> Result<(Vec<Pattern>, Option<Box<Pattern>>, Option<usize>)> {
+        let mut patterns = Vec::new();
+        let mut rest = None;
+        let mut whole = None;
+        let mut i = 0;
+        while i < items.len() {
+            let item = &items[i];
+            if is_sym(item, "&") || is_kw(item, "as") {
+                let target = match items.get(i + 1) {
+                    Some(target) => target,
+                    None => bail!("Missing pattern after {} in {}", pattern_str(item), pattern_str(pattern)),
+                };
+                if is_kw(item, "as") {
+                    let sym = as_symbol(target, pattern)?;
+                    if i + 2 != items.len() {
+                        bail!(":as must come last in {}", pattern_str(pattern));
+                    }
+                    whole = Some(self.add_local(sym));
+                } else {
+                    if rest.is_some() {
+                        bail!("& used twice in {}", pattern_str(pattern));
+                    }
+                    rest = Some(Box::new(self.pattern(target, ctx)?));
+                }
+                i += 2;
+            } else {
+                if rest.is_some() {
+                    bail!("Got more than one pattern after & in {}", pattern_str(pattern));
+                }
+                patterns.push(self.pattern(item, ctx)?);
+                i += 1;
+            }
+        }
+        Ok((patterns, rest, whole))
+    }
+
+    fn map_pattern(&mut self, map: &MalMap, pattern: &Mal, ctx: Ctx) -> Result<Pattern> {
+        let defaults = match map.get(&Keyword::new("or").into()) {
+            Some(Mal::Map(defaults)) => {
+                for name in defaults.keys() {
+                    as_symbol(name, pattern)?;
+                }
+                Some(defaults)
+            }
+            Some(_) => bail!(":or takes a map of defaults in {}", pattern_str(pattern)),
+            None => None,
+        };
+        let mut entries = Vec::new();
+        let mut whole = None;
+        for (key, item) in map {
+            if is_kw(key, "keys") || is_kw(key, "strs") || is_kw(key, "syms") {
+                let names = match *item {
+                    Mal::Arr(ref names) => names,
+                    _ => bail!("{} takes a vector of symbols in {}", pattern_str(key), pattern_str(pattern)),
+                };
+                for name in names.iter() {
+                    let sym = as_symbol(name, pattern)?;
+                    let lookup_key = if is_kw(key, "keys") {
+                        Keyword::new(sym.text()).into()
+                    } else if is_kw(key, "strs") {
+                        Mal::Str(sym.text().into())
+                    } else {
+                        Mal::Sym(sym.clone())
+                    };
+                    let default = self.default(defaults, name, ctx)?;
+                    let pattern = Pattern::Slot(self.add_local(sym));
+                    entries.push(MapEntry { key: lookup_key, pattern, default });
+                }
+            } else if is_kw(key, "as") {
+                whole = Some(self.add_local(as_symbol(item, pattern)?));
+            } else if is_kw(key, "or") {
+                continue;
+            } else {
+                let default = self.default(defaults, key, ctx)?;
+                let pattern = self.pattern(key, ctx)?;
+                entries.push(MapEntry { key: item.clone(), pattern, default });
+            }
+        }
+        Ok(Pattern::Map { entries, whole, source: pattern.clone() })
+    }
+
+    /// The default from `:or` for a name of a map pattern.
+    fn default(&mut self, defaults: Option<&MalMap>, name: &Mal, ctx: Ctx) -> Result<Option<Node>> {
+        match defaults.and_then(|defaults| defaults.get(name)) {
+            Some(default) => Ok(Some(self.form(default, ctx.non_tail())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Analyzes a `match` pattern, adding the locals it binds.
+    fn match_pattern(&mut self, pattern: &Mal) -> Result<MatchPattern> {
+        match *pattern {
+            Mal::Sym(ref sym) if sym.text() == "_" => Ok(MatchPattern::Any),
+            Mal::Sym(ref sym) => Ok(MatchPattern::Bind(self.add_local(sym))),
+            Mal::List(ref list) => self.match_seq(&list.items, pattern),
+            Mal::Arr(ref arr) => self.match_seq(&arr.items, pattern),
+            Mal::Map(ref map) => {
+                let mut entries = Vec::new();
+                for (key, item) in map {
+                    entries.push((key.clone(), self.match_pattern(item)?));
+                }
+                Ok(MatchPattern::Map(entries))
+            }
+            Mal::Set(_) | Mal::Lazy(_) | Mal::Fn(_) => {
+                bail!("Invalid match pattern: {}", pattern_str(pattern))
+            }
+            ref literal => Ok(MatchPattern::Literal(literal.clone())),
+        }
+    }
+
+    fn match_seq(&mut self, items: &Vector<Mal>, pattern: &Mal) -> Result<MatchPattern> {
+        let mut patterns = Vec::new();
+        let mut rest = None;
+        let mut whole = None;
+        let mut i = 0;
+        while i < items.len() {
+            let item = &items[i];
+            if is_sym(item, "&") || is_kw(item, "as") {
+                let target = items.get(i + 1).ok_or_else(|| {
+                    Error::from(format!("Missing pattern after {} in {}", pattern_str(item), pattern_str(pattern)))
+                })?;
+                if is_kw(item, "as") {
+                    whole = Some(self.add_local(as_symbol(target, pattern)?));
+                } else {
+                    rest = Some(Box::new(self.match_pattern(target)?));
+                }
+                i += 2;
+            } else {
+                patterns.push(self.match_pattern(item)?);
+                i += 1;
+            }
+        }
+        Ok(MatchPattern::Seq { items: patterns, rest, whole })
+    }
+}
+
+fn is_const(node: &Node) -> bool {
+    matches!(*node, Node::Const(_))
+}
+
+/// Parses a namespace to require, given as `foo.bar` or `[foo.bar :as bar]`.
+fn require_spec(spec: &Mal) -> Result<RequireSpec> {
+    let invalid = || format!("require: Invalid spec {}", pattern_str(spec));
+    match *spec {
+        Mal::Sym(ref ns) => Ok(RequireSpec { ns: ns.clone(), alias: None }),
+        Mal::Arr(ref spec) => {
+            let ns = match spec.front() {
+                Some(Mal::Sym(ns)) => ns.clone(),
+                _ => bail!(invalid()),
+            };
+            match (spec.len(), spec.get(1), spec.get(2)) {
+                (1, _, _) => Ok(RequireSpec { ns, alias: None }),
+                (3, Some(as_kw), Some(Mal::Sym(alias))) if is_kw(as_kw, "as") => {
+                    Ok(RequireSpec { ns, alias: Some(alias.clone()) })
+                }
+                _ => bail!(invalid()),
+            }
+        }
+        _ => bail!(invalid()),
+    }
+}
+
+/// Expands `(time expr)` into a `let*` that prints how long the expression
+/// took and returns its value. The locals have names the reader can't
+/// produce, so they can't hide the locals of the expression.
+fn time_form(expr: &Mal) -> Mal {
+    let start = Symbol::new("time start");
+    let value = Symbol::new("time value");
+    let now = || list_with_sym!["core/time-ms"];
+    let bindings: MalArr = vec![
+        start.clone().into(), now().into(),
+        value.clone().into(), expr.clone(),
+    ].into_iter().collect();
+    let elapsed = list_with_sym!["core/-", now(), start];
+    let message = list_with_sym!["core/str", Mal::Str("Elapsed time: ".into()), elapsed,
+        Mal::Str(" msecs".into())];
+    list_with_sym!["let*", bindings, list_with_sym!["core/prn", message], value].into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::core_env;
+    use interpreter::Interpreter;
+    use reader::read_str;
+
+    fn analyze_str(text: &str) -> Result<(Node, usize)> {
+        analyze(&read_str(text)?, &core_env())
+    }
+
+    fn analyze_err(text: &str) -> String {
+        match analyze_str(text) {
+            Ok(node) => panic!("{} was analyzed as {:?}", text, node),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// The lambda a form is analyzed to.
+    fn lambda(text: &str) -> Rc<Lambda> {
+        match analyze_str(text).unwrap().0 {
+            Node::Fn(lambda) => lambda,
+            other => panic!("Not a function: {:?}", other),
+        }
+    }
+
+    fn body(lambda: &Lambda) -> &Node {
+        match lambda.arities[0].body {
+            Body::Tree(ref node) => node,
+            Body::Code(_) => panic!("Not a tree"),
+        }
+    }
+
+    fn call_args(node: &Node) -> &[Node] {
+        match *node {
+            Node::Call { ref args, .. } => args,
+            ref other => panic!("Not a call: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locals_get_slots_in_their_frame() {
+        let (node, nslots) = analyze_str("(let* [a 1 b 2 a 3] (+ a b))").unwrap();
+        assert_eq!(nslots, 3);
+        match node {
+            Node::Let(ref bindings, ref body) => {
+                let slots: Vec<_> = bindings.iter().map(|binding| match binding.0 {
+                    Pattern::Slot(slot) => slot,
+                    ref other => panic!("Not a slot: {:?}", other),
+                }).collect();
+                assert_eq!(slots, vec![0, 1, 2]);
+                // The second a shadows the first.
+                let args = call_args(body);
+                assert!(matches!(args[0], Node::Local(2)), "{:?}", args[0]);
+                assert!(matches!(args[1], Node::Local(1)), "{:?}", args[1]);
+            }
+            other => panic!("Not a let*: {:?}", other),
+        }
+        assert!(matches!(analyze_str("(let* [a 1] a)").unwrap().0, Node::Let(..)));
+        assert!(matches!(analyze_str("undefined-yet").unwrap().0, Node::Global(..)));
+    }
+
+    #[test]
+    fn enclosing_locals_become_upvalues() {
+        let outer = lambda("(fn* [x] (fn* [y] (fn* [] (+ x y))))");
+        assert!(outer.captures.is_empty());
+        let middle = match *body(&outer) {
+            Node::Fn(ref middle) => middle.clone(),
+            ref other => panic!("Not a function: {:?}", other),
+        };
+        // x is captured from the outer frame, and then from the middle one.
+        assert_eq!(middle.captures, vec![Capture::Local(0)]);
+        let inner = match *body(&middle) {
+            Node::Fn(ref inner) => inner.clone(),
+            ref other => panic!("Not a function: {:?}", other),
+        };
+        assert_eq!(inner.captures, vec![Capture::Upvalue(0), Capture::Local(0)]);
+        let args = call_args(body(&inner));
+        assert!(matches!(args[0], Node::Upvalue(0)), "{:?}", args[0]);
+        assert!(matches!(args[1], Node::Upvalue(1)), "{:?}", args[1]);
+    }
+
+    #[test]
+    fn local_functions_refer_to_themselves() {
+        let outer = lambda("(fn* [] (def! f (fn* [n] (f n))))");
+        let inner = match *body(&outer) {
+            Node::DefLocal(_, ref value) => match **value {
+                Node::Fn(ref inner) => inner.clone(),
+                ref other => panic!("Not a function: {:?}", other),
+            },
+            ref other => panic!("Not a local def!: {:?}", other),
+        };
+        assert!(inner.captures.is_empty());
+        assert!(matches!(body(&inner), Node::Call { func, tail: true, .. } if matches!(**func, Node::SelfRef)));
+    }
+
+    #[test]
+    fn recur_must_be_in_tail_position() {
+        assert!(analyze_err("(recur 1)").contains("Not inside a loop or fn*"));
+        assert!(analyze_err("(loop [i 0] (+ 1 (recur i)))").contains("tail position"));
+        assert!(analyze_err("(fn* [x] (do (recur x) x))").contains("tail position"));
+        assert!(analyze_err("(loop [i 0] (if (recur i) 1 2))").contains("tail position"));
+        // A lazy-seq body is a function of its own.
+        assert!(analyze_err("(loop [i 0] (lazy-seq (recur i)))").contains("Not inside a loop"));
+
+        assert!(analyze_str("(loop [i 0] (if i (recur i) (do 1 (let* [x 1] (recur x)))))").is_ok());
+        assert!(analyze_str("(fn* [x] (match x 1 (recur 2) _ x))").is_ok());
+        // recur in a nested fn* goes back to that function.
+        assert!(analyze_str("(loop [i 0] (fn* [] (recur)))").is_ok());
+    }
+
+    #[test]
+    fn recur_takes_as_many_values_as_its_target() {
+        assert!(analyze_err("(loop [i 0] (recur))").contains("Expected 1 arguments, found 0"));
+        assert!(analyze_err("(fn* [a b] (recur 1))").contains("Expected 2 arguments, found 1"));
+        assert!(analyze_err("(fn* [a & more] (recur))").contains("Expected 1 or more arguments"));
+        assert!(analyze_str("(fn* [a & more] (recur 1 2 3))").is_ok());
+        assert!(analyze_str("(fn* ([a] (recur a)) ([a b] (recur a b)))").is_ok());
+    }
+
+    fn arities(text: &str) -> Vec<Arity> {
+        fn_args(text).iter().map(|clause| {
+            let items: Vec<Mal> = clause.clone().list().unwrap().iter().cloned().collect();
+            let params = match items[0] {
+                Mal::Arr(ref params) => params.clone(),
+                ref other => panic!("Not a parameter vector: {:?}", other),
+            };
+            Arity { params, body: body_form(&items[1..]) }
+        }).collect()
+    }
+
+    #[test]
+    fn arities_must_not_be_ambiguous() {
+        assert!(check_arities(&arities("(fn* ([] 0) ([a] 1) ([a b & c] 2))")).is_ok());
+        assert!(check_arities(&arities("(fn* ([a] 1) ([b] 2))")).unwrap_err().to_string()
+            .contains("More than one arity takes 1 arguments"));
+        assert!(check_arities(&arities("(fn* ([& a] 1) ([b & c] 2))")).unwrap_err().to_string()
+            .contains("Only one arity can take a variable number"));
+        assert!(check_arities(&arities("(fn* ([a & b] 1) ([a b c] 2))")).unwrap_err().to_string()
+            .contains("can't take more arguments than the variadic one"));
+        // Equal to the variadic one is fine, the fixed one is preferred.
+        assert!(check_arities(&arities("(fn* ([a & b] 1) ([a] 2))")).is_ok());
+        assert!(analyze_err("(fn* ([a] 1) ([b] 2))").contains("More than one arity"));
+    }
+
+    fn eval(interpreter: &mut Interpreter, text: &str) -> Result<Mal> {
+        interpreter.eval(&read_str(text)?)
+    }
+
+    fn fn_args(text: &str) -> Vec<Mal> {
+        match read_str(text).unwrap() {
+            Mal::List(list) => list.iter().skip(1).cloned().collect(),
+            other => panic!("Not a list: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multi_arity_needs_parameter_vectors() {
+        assert!(is_multi_arity(&fn_args("(fn* ([x] x) ([x y] y))")));
+        assert!(is_multi_arity(&fn_args("(fn* ([x] x))")));
+        assert!(! is_multi_arity(&fn_args("(fn* ([a b]) ((id +) a b))")));
+        assert!(! is_multi_arity(&fn_args("(fn* (x) (x))")));
+
+        let mut interpreter = Interpreter::new();
+        eval(&mut interpreter, "(def! id (fn* [x] x))").unwrap();
+        assert_eq!(eval(&mut interpreter, "((fn* ([a b]) ((id +) a b)) [1 2])").unwrap(), Mal::Num(3.0));
+    }
+}
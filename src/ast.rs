@@ -0,0 +1,153 @@
+//! The analyzed form of code, as produced by the analyzer and run by the
+//! evaluator.
+//!
+//! Special forms are resolved to their own nodes, locals to slots in the
+//! frame of the function they belong to, and globals to their vars.
+use std::rc::Rc;
+use types::{Mal, Symbol, Arity};
+use env::{Env, Var};
+use vm::Chunk;
+
+#[derive(Debug)]
+pub enum Node {
+    /// A value that needs no evaluation.
+    Const(Mal),
+    /// A local of the current frame.
+    Local(usize),
+    /// A local of an enclosing function, captured by the running closure.
+    Upvalue(usize),
+    /// The running closure itself, as a local `def!` of a function refers to it.
+    SelfRef,
+    /// A global, with the symbol it was written as.
+    Global(Var, Symbol),
+    Vector(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+    Set(Vec<Node>),
+    If(Box<Node>, Box<Node>, Option<Box<Node>>),
+    /// A non-empty body, where the last node gives the value.
+    Do(Vec<Node>),
+    Let(Vec<(Pattern, Node)>, Box<Node>),
+    Loop(Vec<(Pattern, Node)>, Box<Node>),
+    Recur(Vec<Node>),
+    Fn(Rc<Lambda>),
+    /// A zero argument lambda whose result is the sequence.
+    LazySeq(Rc<Lambda>),
+    /// Binds a global.
+    Def(Env, Var, Box<Node>),
+    /// Binds the globals of a pattern, through the slots the pattern binds.
+    DefPattern(Env, Pattern, Vec<(Var, usize)>, Box<Node>),
+    /// A `def!` inside a local scope, which binds locals instead.
+    DefLocal(Pattern, Box<Node>),
+    Match(Box<Node>, Vec<MatchClause>),
+    Ns(Env, Symbol, Vec<RequireSpec>),
+    InNs(Env, Symbol),
+    Require(Env, Vec<RequireSpec>),
+    /// Evaluates the forms of the file at the path, in the current namespace.
+    LoadFile(Env, Box<Node>),
+    Call {
+        func: Box<Node>,
+        args: Vec<Node>,
+        /// Calls in tail position return to the caller's loop instead of
+        /// growing the stack.
+        tail: bool,
+    },
+}
+
+/// A namespace to require, and the alias to give it.
+#[derive(Debug)]
+pub struct RequireSpec {
+    pub ns: Symbol,
+    pub alias: Option<Symbol>,
+}
+
+/// How a closure gets the value of one of its upvalues when it is made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    Local(usize),
+    Upvalue(usize),
+    SelfRef,
+}
+
+/// The analyzed form of a `fn*`.
+#[derive(Debug)]
+pub struct Lambda {
+    pub name: Option<Symbol>,
+    pub arities: Vec<LambdaArity>,
+    /// The arities as written, for printing and comparing functions.
+    pub source: Vec<Arity>,
+    pub captures: Vec<Capture>,
+    /// The number of slots of a frame, shared by all arities.
+    pub nslots: usize,
+}
+
+#[derive(Debug)]
+pub struct LambdaArity {
+    pub params: Vec<Pattern>,
+    pub rest: Option<Pattern>,
+    pub body: Body,
+}
+
+/// The code of an arity, as run by either engine.
+#[derive(Debug)]
+pub enum Body {
+    Tree(Node),
+    Code(Chunk),
+}
+
+impl LambdaArity {
+    pub fn accepts(&self, nargs: usize) -> bool {
+        match self.rest {
+            Some(_) => nargs >= self.params.len(),
+            None => nargs == self.params.len(),
+        }
+    }
+}
+
+/// A destructuring pattern, with its symbols resolved to slots.
+#[derive(Debug)]
+pub enum Pattern {
+    Slot(usize),
+    Seq {
+        items: Vec<Pattern>,
+        rest: Option<Box<Pattern>>,
+        /// The slot of `:as`.
+        whole: Option<usize>,
+        source: Mal,
+    },
+    Map {
+        entries: Vec<MapEntry>,
+        whole: Option<usize>,
+        source: Mal,
+    },
+}
+
+/// A key to look up in a map pattern, and the pattern to bind it to.
+#[derive(Debug)]
+pub struct MapEntry {
+    pub key: Mal,
+    pub pattern: Pattern,
+    /// The value of a missing key, from `:or`.
+    pub default: Option<Node>,
+}
+
+/// A `match` pattern, with its symbols resolved to slots.
+#[derive(Debug)]
+pub enum MatchPattern {
+    /// The `_` pattern.
+    Any,
+    Bind(usize),
+    Literal(Mal),
+    Seq {
+        items: Vec<MatchPattern>,
+        rest: Option<Box<MatchPattern>>,
+        whole: Option<usize>,
+    },
+    Map(Vec<(Mal, MatchPattern)>),
+}
+
+#[derive(Debug)]
+pub struct MatchClause {
+    pub pattern: MatchPattern,
+    pub guard: Option<Node>,
+    pub body: Node,
+}
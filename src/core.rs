@@ -1,7 +1,18 @@
-use types::{Mal, MalList};
+use types::{Mal, MalList, MalArr, MalMap, MalSet, MalRegex, MalAtom, LazySeq, Keyword};
+use regex::Captures;
 use env::Env;
 use printer;
+use eval;
+use reader;
+use gc;
+use limits;
+use sandbox;
 use errors::*;
+use std::env as std_env;
+use std::fs;
+use std::mem;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Returns the core environment.
 pub fn core_env() -> Env {
@@ -21,14 +32,1041 @@ pub fn core_env() -> Env {
     env.add_native_func(">", gt).unwrap();
     env.add_native_func(">=", ge).unwrap();
     env.add_native_func("pr-str", pr_str).unwrap();
+    env.add_native_func("read-string", read_string).unwrap();
     env.add_native_func("str", str_).unwrap();
     env.add_native_func("prn", prn).unwrap();
     env.add_native_func("println", println).unwrap();
+    env.add_native_func("hash-map", hash_map).unwrap();
+    env.add_native_func("sorted-map", sorted_map).unwrap();
+    env.add_native_func("map?", mapp).unwrap();
+    env.add_native_func("sorted?", sortedp).unwrap();
+    env.add_native_func("keys", keys).unwrap();
+    env.add_native_func("vals", vals).unwrap();
+    env.add_native_func("get", get).unwrap();
+    env.add_native_func("assoc", assoc).unwrap();
+    env.add_native_func("dissoc", dissoc).unwrap();
+    env.add_native_func("conj", conj).unwrap();
+    env.add_native_func("contains?", containsp).unwrap();
+    env.add_native_func("set", set).unwrap();
+    env.add_native_func("set?", setp).unwrap();
+    env.add_native_func("disj", disj).unwrap();
+    env.add_native_func("union", union).unwrap();
+    env.add_native_func("intersection", intersection).unwrap();
+    env.add_native_func("difference", difference).unwrap();
+    env.add_native_func("seq", seq).unwrap();
+    env.add_native_func("string?", stringp).unwrap();
+    env.add_native_func("char?", charp).unwrap();
+    env.add_native_func("char", char_).unwrap();
+    env.add_native_func("int", int).unwrap();
+    env.add_native_func("subs", subs).unwrap();
+    env.add_native_func("string/split", split).unwrap();
+    env.add_native_func("string/join", join).unwrap();
+    env.add_native_func("upper-case", upper_case).unwrap();
+    env.add_native_func("lower-case", lower_case).unwrap();
+    env.add_native_func("trim", trim).unwrap();
+    env.add_native_func("starts-with?", starts_withp).unwrap();
+    env.add_native_func("ends-with?", ends_withp).unwrap();
+    env.add_native_func("includes?", includesp).unwrap();
+    env.add_native_func("index-of", index_of).unwrap();
+    env.add_native_func("replace", replace).unwrap();
+    env.add_native_func("regex?", regexp).unwrap();
+    env.add_native_func("re-pattern", re_pattern).unwrap();
+    env.add_native_func("re-find", re_find).unwrap();
+    env.add_native_func("re-matches", re_matches).unwrap();
+    env.add_native_func("re-seq", re_seq).unwrap();
+    env.add_native_func("bytes", bytes).unwrap();
+    env.add_native_func("bytes?", bytesp).unwrap();
+    env.add_native_func("byte-count", byte_count).unwrap();
+    env.add_native_func("byte-at", byte_at).unwrap();
+    env.add_native_func("subbytes", subbytes).unwrap();
+    env.add_native_func("bytes->string", bytes_to_string).unwrap();
+    env.add_native_func("string->bytes", string_to_bytes).unwrap();
+    env.add_native_func("slurp-bytes", slurp_bytes).unwrap();
+    env.add_native_func("spit-bytes", spit_bytes).unwrap();
+    env.add_native_func("sh", sh).unwrap();
+    env.add_native_func("getenv", getenv).unwrap();
+    env.add_native_func("cons", cons).unwrap();
+    env.add_native_func("first", first).unwrap();
+    env.add_native_func("rest", rest).unwrap();
+    env.add_native_func("range", range).unwrap();
+    env.add_native_func("iterate", iterate).unwrap();
+    env.add_native_func("repeat", repeat).unwrap();
+    env.add_native_func("cycle", cycle).unwrap();
+    env.add_native_func("take", take).unwrap();
+    env.add_native_func("drop", drop).unwrap();
+    env.add_native_func("take-while", take_while).unwrap();
+    env.add_native_func("doall", doall).unwrap();
+    env.add_native_func("map", map).unwrap();
+    env.add_native_func("filter", filter).unwrap();
+    env.add_native_func("concat", concat).unwrap();
+    env.add_native_func("atom", atom).unwrap();
+    env.add_native_func("atom?", atomp).unwrap();
+    env.add_native_func("deref", deref).unwrap();
+    env.add_native_func("reset!", reset).unwrap();
+    env.add_native_func("swap!", swap).unwrap();
+    env.add_native_func("time-ms", time_ms).unwrap();
+    env.add_native_func("gc", gc_).unwrap();
+    env.add_native_func("gc-stats", gc_stats).unwrap();
     env
 }
 
+fn fill_map(name: &str, mut map: MalMap, args: &mut MalList) -> Result<Mal> {
+    if ! args.len().is_multiple_of(2) {
+        bail!("'{}' takes an even number of arguments, found {}", name, args.len());
+    }
+    while ! args.is_empty() {
+        let key = args.pop_front().unwrap();
+        let value = args.pop_front().unwrap();
+        map.insert(key, value);
+    }
+    Ok(map.into())
+}
+
+fn hash_map(args: &mut MalList) -> Result<Mal> {
+    fill_map("hash-map", MalMap::new(), args)
+}
+
+fn sorted_map(args: &mut MalList) -> Result<Mal> {
+    fill_map("sorted-map", MalMap::sorted(), args)
+}
+
+fn mapp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("map?", 1, args)?;
+    let arg = args.pop_front().unwrap();
+    Ok(matches!(arg, Mal::Map(_)).into())
+}
+
+fn sortedp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("sorted?", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::Map(ref map) => Ok(map.is_sorted().into()),
+        _ => Ok(false.into()),
+    }
+}
+
+fn keys(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("keys", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::Map(ref map) => Ok(map.keys().cloned().collect::<MalList>().into()),
+        ref other => bail!("'keys' takes a hashmap, found {}", other.type_name()),
+    }
+}
+
+fn vals(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("vals", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::Map(ref map) => Ok(map.values().cloned().collect::<MalList>().into()),
+        ref other => bail!("'vals' takes a hashmap, found {}", other.type_name()),
+    }
+}
+
+fn get(args: &mut MalList) -> Result<Mal> {
+    if ! (args.len() == 2 || args.len() == 3) {
+        bail!("'get' takes 2 or 3 arguments, found {}", args.len());
+    }
+    let coll = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    let default = args.pop_front().unwrap_or(Mal::Nil);
+    match coll {
+        Mal::Map(ref map) => Ok(map.get(&key).cloned().unwrap_or(default)),
+        Mal::Nil => Ok(default),
+        ref other => bail!("'get' takes a hashmap (or nil), found {}", other.type_name()),
+    }
+}
+
+fn assoc(args: &mut MalList) -> Result<Mal> {
+    if args.is_empty() {
+        bail!("'assoc' takes a hashmap and key-value pairs, found no arguments");
+    }
+    // The new map shares its structure with the original.
+    match args.pop_front().unwrap() {
+        Mal::Map(map) => fill_map("assoc", map, args),
+        Mal::Nil => fill_map("assoc", MalMap::new(), args),
+        ref other => bail!("'assoc' takes a hashmap (or nil), found {}", other.type_name()),
+    }
+}
+
+fn dissoc(args: &mut MalList) -> Result<Mal> {
+    if args.is_empty() {
+        bail!("'dissoc' takes a hashmap and keys, found no arguments");
+    }
+    match args.pop_front().unwrap() {
+        Mal::Map(mut map) => {
+            for key in args.iter() {
+                map.remove(key);
+            }
+            Ok(map.into())
+        }
+        Mal::Nil => Ok(Mal::Nil),
+        ref other => bail!("'dissoc' takes a hashmap (or nil), found {}", other.type_name()),
+    }
+}
+
+fn conj(args: &mut MalList) -> Result<Mal> {
+    if args.is_empty() {
+        bail!("'conj' takes a collection and items, found no arguments");
+    }
+    let coll = match args.pop_front().unwrap() {
+        Mal::Nil => MalList::new().into(),
+        other => other,
+    };
+    match coll {
+        Mal::List(mut list) => {
+            for item in args.iter() {
+                list.push_front(item.clone());
+            }
+            Ok(list.into())
+        }
+        Mal::Arr(mut arr) => {
+            arr.append(args.inner().clone());
+            Ok(arr.into())
+        }
+        Mal::Map(mut map) => {
+            for item in args.iter() {
+                match *item {
+                    Mal::Arr(ref pair) if pair.len() == 2 => {
+                        map.insert(pair[0].clone(), pair[1].clone());
+                    }
+                    ref other => bail!("'conj' on a hashmap takes [key value] arrays, found {}", 
+                        other.type_name()),
+                }
+            }
+            Ok(map.into())
+        }
+        Mal::Set(mut set) => {
+            for item in args.iter() {
+                set.insert(item.clone());
+            }
+            Ok(set.into())
+        }
+        ref other => bail!("'conj' takes a collection (or nil), found {}", other.type_name()),
+    }
+}
+
+fn containsp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("contains?", 2, args)?;
+    let coll = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    match coll {
+        Mal::Map(ref map) => Ok(map.contains_key(&key).into()),
+        Mal::Set(ref set) => Ok(set.contains(&key).into()),
+        Mal::Nil => Ok(false.into()),
+        ref other => bail!("'contains?' takes a hashmap or set (or nil), found {}", other.type_name()),
+    }
+}
+
+fn set(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("set", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::List(list) => Ok(list.iter().cloned().collect::<MalSet>().into()),
+        Mal::Arr(arr) => Ok(arr.iter().cloned().collect::<MalSet>().into()),
+        Mal::Set(set) => Ok(set.into()),
+        Mal::Nil => Ok(MalSet::new().into()),
+        ref other => bail!("'set' takes a list, array or set (or nil), found {}", other.type_name()),
+    }
+}
+
+fn setp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("set?", 1, args)?;
+    let arg = args.pop_front().unwrap();
+    Ok(matches!(arg, Mal::Set(_)).into())
+}
+
+fn disj(args: &mut MalList) -> Result<Mal> {
+    if args.is_empty() {
+        bail!("'disj' takes a set and items, found no arguments");
+    }
+    match args.pop_front().unwrap() {
+        Mal::Set(mut set) => {
+            for item in args.iter() {
+                set.remove(item);
+            }
+            Ok(set.into())
+        }
+        Mal::Nil => Ok(Mal::Nil),
+        ref other => bail!("'disj' takes a set (or nil), found {}", other.type_name()),
+    }
+}
+
+/// Checks that all arguments are sets, and returns them in order.
+fn set_args(name: &str, args: &mut MalList) -> Result<Vec<MalSet>> {
+    if args.is_empty() {
+        bail!("'{}' takes 1 or more arguments, found 0", name);
+    }
+    let mut sets = Vec::with_capacity(args.len());
+    for arg in mem::take(args.inner()) {
+        match arg {
+            Mal::Set(set) => sets.push(set),
+            ref other => bail!("'{}' takes sets, found {}", name, other.type_name()),
+        }
+    }
+    Ok(sets)
+}
+
+fn union(args: &mut MalList) -> Result<Mal> {
+    let mut sets = set_args("union", args)?.into_iter();
+    let mut res = sets.next().unwrap();
+    for set in sets {
+        for item in set {
+            res.insert(item);
+        }
+    }
+    Ok(res.into())
+}
+
+fn intersection(args: &mut MalList) -> Result<Mal> {
+    let mut sets = set_args("intersection", args)?.into_iter();
+    let first = sets.next().unwrap();
+    let others = sets.collect::<Vec<_>>();
+    let res = first.into_iter()
+        .filter(|item| others.iter().all(|set| set.contains(item)))
+        .collect::<MalSet>();
+    Ok(res.into())
+}
+
+fn difference(args: &mut MalList) -> Result<Mal> {
+    let mut sets = set_args("difference", args)?.into_iter();
+    let mut res = sets.next().unwrap();
+    for set in sets {
+        for item in set.iter() {
+            res.remove(item);
+        }
+    }
+    Ok(res.into())
+}
+
+fn seq(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("seq", 1, args)?;
+    let coll = args.pop_front().unwrap();
+    match coll {
+        Mal::List(ref list) if ! list.is_empty() => Ok(coll),
+        // Only the first item of a lazy sequence is realized.
+        Mal::Lazy(ref lazy) => {
+            match lazy.step()? {
+                Some(_) => Ok(coll),
+                None => Ok(Mal::Nil),
+            }
+        }
+        Mal::Fn(_) | Mal::Num(_) | Mal::Sym(_) | Mal::Kw(_) | Mal::Bool(_) | Mal::Char(_) | Mal::Regex(_) => {
+            bail!("'seq' takes a collection or string (or nil), found {}", coll.type_name())
+        }
+        _ => {
+            let items = coll.seq_items()?;
+            if items.is_empty() {
+                Ok(Mal::Nil)
+            } else {
+                Ok(MalList::from(items).into())
+            }
+        }
+    }
+}
+
+fn stringp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("string?", 1, args)?;
+    let arg = args.pop_front().unwrap();
+    Ok(matches!(arg, Mal::Str(_)).into())
+}
+
+fn charp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("char?", 1, args)?;
+    let arg = args.pop_front().unwrap();
+    Ok(matches!(arg, Mal::Char(_)).into())
+}
+
+fn char_(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("char", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::Char(ch) => Ok(ch.into()),
+        Mal::Num(num) => {
+            let code = num as u32;
+            match ::std::char::from_u32(code) {
+                Some(ch) if code as f64 == num => Ok(ch.into()),
+                _ => bail!("'char': {} is not a valid unicode code point", num),
+            }
+        }
+        ref other => bail!("'char' takes a number or char, found {}", other.type_name()),
+    }
+}
+
+fn int(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("int", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::Char(ch) => Ok((ch as u32 as f64).into()),
+        Mal::Num(num) => Ok(num.trunc().into()),
+        ref other => bail!("'int' takes a number or char, found {}", other.type_name()),
+    }
+}
+
+/// Returns the byte offset of the given character index in the string.
+fn char_offset(string: &str, index: usize) -> Option<usize> {
+    if index == 0 {
+        return Some(0);
+    }
+    match string.char_indices().nth(index) {
+        Some((offset, _)) => Some(offset),
+        None if string.chars().count() == index => Some(string.len()),
+        None => None,
+    }
+}
+
+/// Converts a number argument to a character index.
+fn index_arg(name: &str, arg: &Mal) -> Result<usize> {
+    let num = arg.number()?;
+    if num < 0.0 || num.fract() != 0.0 {
+        bail!("'{}': invalid index {}", name, num);
+    }
+    Ok(num as usize)
+}
+
+fn subs(args: &mut MalList) -> Result<Mal> {
+    if ! (args.len() == 2 || args.len() == 3) {
+        bail!("'subs' takes 2 or 3 arguments, found {}", args.len());
+    }
+    let string = args[0].string()?;
+    let start = index_arg("subs", &args[1])?;
+    let start_offset = char_offset(string, start)
+        .ok_or_else(|| format!("'subs': start index {} out of range", start))?;
+    let end_offset = if let Some(end) = args.get(2) {
+        let end = index_arg("subs", end)?;
+        if end < start {
+            bail!("'subs': end index {} is before start index {}", end, start);
+        }
+        char_offset(string, end)
+            .ok_or_else(|| format!("'subs': end index {} out of range", end))?
+    } else {
+        string.len()
+    };
+    Ok(string[start_offset..end_offset].to_string().into())
+}
+
+/// Converts a string or character argument to a string pattern.
+fn pattern_arg(name: &str, arg: &Mal) -> Result<String> {
+    match *arg {
+        Mal::Str(ref string) => Ok(string.clone()),
+        Mal::Char(ch) => Ok(ch.to_string()),
+        ref other => bail!("'{}' takes a string or char pattern, found {}", name, other.type_name()),
+    }
+}
+
+fn split(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("string/split", 2, args)?;
+    let string = args[0].string()?;
+    let parts: MalArr = if let Mal::Regex(ref regex) = args[1] {
+        regex.split(string).map(|part| part.to_string().into()).collect()
+    } else {
+        let sep = pattern_arg("string/split", &args[1])?;
+        if sep.is_empty() {
+            string.chars().map(|ch| ch.to_string().into()).collect()
+        } else {
+            string.split(sep.as_str()).map(|part| part.to_string().into()).collect()
+        }
+    };
+    Ok(parts.into())
+}
+
+fn join(args: &mut MalList) -> Result<Mal> {
+    if ! (args.len() == 1 || args.len() == 2) {
+        bail!("'string/join' takes 1 or 2 arguments, found {}", args.len());
+    }
+    let coll = args.pop_back().unwrap();
+    let sep = match args.pop_front() {
+        Some(sep) => pattern_arg("string/join", &sep)?,
+        None => String::new(),
+    };
+    let items = match coll {
+        Mal::List(list) => list.items.clone(),
+        Mal::Arr(arr) => arr.items.clone(),
+        Mal::Nil => return Ok(String::new().into()),
+        ref other => bail!("'string/join' takes a list or array, found {}", other.type_name()),
+    };
+    let mut string = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i != 0 {
+            string.push_str(&sep);
+        }
+        printer::pr_str_into(item, &mut string, false)?;
+    }
+    Ok(string.into())
+}
+
+fn upper_case(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("upper-case", 1, args)?;
+    Ok(args[0].string()?.to_uppercase().into())
+}
+
+fn lower_case(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("lower-case", 1, args)?;
+    Ok(args[0].string()?.to_lowercase().into())
+}
+
+fn trim(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("trim", 1, args)?;
+    Ok(args[0].string()?.trim().to_string().into())
+}
+
+fn starts_withp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("starts-with?", 2, args)?;
+    let prefix = pattern_arg("starts-with?", &args[1])?;
+    Ok(args[0].string()?.starts_with(prefix.as_str()).into())
+}
+
+fn ends_withp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("ends-with?", 2, args)?;
+    let suffix = pattern_arg("ends-with?", &args[1])?;
+    Ok(args[0].string()?.ends_with(suffix.as_str()).into())
+}
+
+fn includesp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("includes?", 2, args)?;
+    let part = pattern_arg("includes?", &args[1])?;
+    Ok(args[0].string()?.contains(part.as_str()).into())
+}
+
+fn index_of(args: &mut MalList) -> Result<Mal> {
+    if ! (args.len() == 2 || args.len() == 3) {
+        bail!("'index-of' takes 2 or 3 arguments, found {}", args.len());
+    }
+    let string = args[0].string()?;
+    let part = pattern_arg("index-of", &args[1])?;
+    let from = match args.get(2) {
+        Some(from) => index_arg("index-of", from)?,
+        None => 0,
+    };
+    let from_offset = match char_offset(string, from) {
+        Some(offset) => offset,
+        None => return Ok(Mal::Nil),
+    };
+    match string[from_offset..].find(part.as_str()) {
+        Some(offset) => {
+            let index = from + string[from_offset..from_offset + offset].chars().count();
+            Ok((index as f64).into())
+        }
+        None => Ok(Mal::Nil),
+    }
+}
+
+fn replace(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("replace", 3, args)?;
+    let string = args[0].string()?;
+    let to = pattern_arg("replace", &args[2])?;
+    if let Mal::Regex(ref regex) = args[1] {
+        // The replacement may refer to groups, eg. "$1".
+        return Ok(regex.replace_all(string, to.as_str()).into_owned().into());
+    }
+    let from = pattern_arg("replace", &args[1])?;
+    if from.is_empty() {
+        bail!("'replace': cannot replace an empty pattern");
+    }
+    Ok(string.replace(from.as_str(), &to).into())
+}
+
+fn regexp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("regex?", 1, args)?;
+    let arg = args.pop_front().unwrap();
+    Ok(matches!(arg, Mal::Regex(_)).into())
+}
+
+fn re_pattern(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("re-pattern", 1, args)?;
+    match args.pop_front().unwrap() {
+        Mal::Regex(regex) => Ok(regex.into()),
+        Mal::Str(ref pattern) => Ok(MalRegex::new(pattern)?.into()),
+        ref other => bail!("'re-pattern' takes a string, found {}", other.type_name()),
+    }
+}
+
+/// Checks the arguments of the regex matching functions.
+fn regex_args<'a>(name: &str, args: &'a MalList) -> Result<(&'a MalRegex, &'a str)> {
+    assert_nargs(name, 2, args)?;
+    let regex = match args[0] {
+        Mal::Regex(ref regex) => regex,
+        ref other => bail!("'{}' takes a regex, found {}", name, other.type_name()),
+    };
+    Ok((regex, args[1].string()?))
+}
+
+/// Returns the whole match if the regex has no groups, and otherwise an
+/// array of the match followed by the groups (nil for unmatched groups).
+fn match_result(captures: &Captures) -> Mal {
+    if captures.len() == 1 {
+        return captures[0].to_string().into();
+    }
+    captures.iter()
+        .map(|group| group.map_or(Mal::Nil, |group| group.as_str().to_string().into()))
+        .collect::<MalArr>()
+        .into()
+}
+
+fn re_find(args: &mut MalList) -> Result<Mal> {
+    let (regex, string) = regex_args("re-find", args)?;
+    Ok(regex.captures(string).map_or(Mal::Nil, |captures| match_result(&captures)))
+}
+
+fn re_matches(args: &mut MalList) -> Result<Mal> {
+    let (regex, string) = regex_args("re-matches", args)?;
+    let whole = regex.captures(string).filter(|captures| {
+        let whole = captures.get(0).unwrap();
+        whole.start() == 0 && whole.end() == string.len()
+    });
+    Ok(whole.map_or(Mal::Nil, |captures| match_result(&captures)))
+}
+
+fn re_seq(args: &mut MalList) -> Result<Mal> {
+    let (regex, string) = regex_args("re-seq", args)?;
+    let matches = regex.captures_iter(string)
+        .map(|captures| match_result(&captures))
+        .collect::<MalList>();
+    if matches.is_empty() {
+        Ok(Mal::Nil)
+    } else {
+        Ok(matches.into())
+    }
+}
+
+fn bytes(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("bytes", 1, args)?;
+    let items = match args.pop_front().unwrap() {
+        Mal::Bytes(bytes) => return Ok(Mal::Bytes(bytes)),
+        Mal::List(list) => list.items.clone(),
+        Mal::Arr(arr) => arr.items.clone(),
+        Mal::Nil => return Ok(Vec::new().into()),
+        ref other => bail!("'bytes' takes a list or array of numbers, found {}", other.type_name()),
+    };
+    let mut bytes = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        let num = item.number()?;
+        if !(0.0..=255.0).contains(&num) || num.fract() != 0.0 {
+            bail!("'bytes': {} is not a byte value (0-255)", num);
+        }
+        bytes.push(num as u8);
+    }
+    Ok(bytes.into())
+}
+
+fn bytesp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("bytes?", 1, args)?;
+    let arg = args.pop_front().unwrap();
+    Ok(matches!(arg, Mal::Bytes(_)).into())
+}
+
+fn byte_count(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("byte-count", 1, args)?;
+    Ok((args[0].bytes()?.len() as f64).into())
+}
+
+fn byte_at(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("byte-at", 2, args)?;
+    let bytes = args[0].bytes()?;
+    let index = index_arg("byte-at", &args[1])?;
+    match bytes.get(index) {
+        Some(&byte) => Ok((byte as f64).into()),
+        None => bail!("'byte-at': index {} out of range for {} bytes", index, bytes.len()),
+    }
+}
+
+fn subbytes(args: &mut MalList) -> Result<Mal> {
+    if ! (args.len() == 2 || args.len() == 3) {
+        bail!("'subbytes' takes 2 or 3 arguments, found {}", args.len());
+    }
+    let bytes = args[0].bytes()?;
+    let start = index_arg("subbytes", &args[1])?;
+    let end = match args.get(2) {
+        Some(end) => index_arg("subbytes", end)?,
+        None => bytes.len(),
+    };
+    if start > end || end > bytes.len() {
+        bail!("'subbytes': range {}..{} out of range for {} bytes", start, end, bytes.len());
+    }
+    Ok(bytes[start..end].to_vec().into())
+}
+
+fn bytes_to_string(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("bytes->string", 1, args)?;
+    let bytes = args[0].bytes()?;
+    let string = ::std::str::from_utf8(bytes)
+        .chain_err(|| "'bytes->string': bytes are not valid UTF-8")?;
+    Ok(string.to_string().into())
+}
+
+fn string_to_bytes(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("string->bytes", 1, args)?;
+    Ok(args[0].string()?.as_bytes().to_vec().into())
+}
+
+fn slurp_bytes(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("slurp-bytes", 1, args)?;
+    let path = args[0].string()?;
+    sandbox::check_read(path)?;
+    let bytes = fs::read(path)
+        .chain_err(|| format!("'slurp-bytes': could not read '{}'", path))?;
+    Ok(bytes.into())
+}
+
+fn spit_bytes(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("spit-bytes", 2, args)?;
+    let path = args[0].string()?;
+    sandbox::check_write(path)?;
+    fs::write(path, args[1].bytes()?)
+        .chain_err(|| format!("'spit-bytes': could not write '{}'", path))?;
+    Ok(Mal::Nil)
+}
+
+/// Runs a command with arguments, and returns its exit code and output:
+/// `(sh "ls" "-a")` gives `{:exit 0 :out "..." :err ""}`.
+fn sh(args: &mut MalList) -> Result<Mal> {
+    if args.is_empty() {
+        bail!("'sh' takes a command and its arguments, found no arguments");
+    }
+    let command = args[0].string()?;
+    sandbox::check_process(command)?;
+    let mut process = Command::new(command);
+    for arg in args.iter().skip(1) {
+        process.arg(arg.string()?);
+    }
+    let output = process.output()
+        .chain_err(|| format!("'sh': could not run '{}'", command))?;
+    let mut map = MalMap::new();
+    map.insert(Keyword::new("exit"), output.status.code().map_or(Mal::Nil, |code| (code as f64).into()));
+    map.insert(Keyword::new("out"), String::from_utf8_lossy(&output.stdout).into_owned());
+    map.insert(Keyword::new("err"), String::from_utf8_lossy(&output.stderr).into_owned());
+    Ok(map.into())
+}
+
+/// Returns the value of an environment variable, or nil if it isn't set.
+fn getenv(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("getenv", 1, args)?;
+    let name = args[0].string()?;
+    sandbox::check_env(name)?;
+    Ok(std_env::var(name).map_or(Mal::Nil, Mal::from))
+}
+
+/// Calls a function with a single argument.
+fn call1(func: &Mal, arg: Mal) -> Result<Mal> {
+    let mut args = MalList::new();
+    args.push_back(arg);
+    eval::call(func, args)
+}
+
+/// Converts a number argument to a count.
+fn count_arg(name: &str, arg: &Mal) -> Result<usize> {
+    let num = arg.number()?;
+    if num < 0.0 || num.fract() != 0.0 {
+        bail!("'{}': invalid count {}", name, num);
+    }
+    Ok(num as usize)
+}
+
+fn cons(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("cons", 2, args)?;
+    let item = args.pop_front().unwrap();
+    match args.pop_front().unwrap() {
+        // Consing onto a lazy sequence must not realize it.
+        Mal::Lazy(lazy) => Ok(LazySeq::cons(item, lazy.into()).into()),
+        coll => {
+            let mut list: MalList = coll.seq_items()?.into();
+            list.push_front(item);
+            Ok(list.into())
+        }
+    }
+}
+
+fn first(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("first", 1, args)?;
+    match args[0].first_rest()? {
+        Some((first, _)) => Ok(first),
+        None => Ok(Mal::Nil),
+    }
+}
+
+fn rest(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("rest", 1, args)?;
+    match args[0].first_rest()? {
+        Some((_, rest)) => Ok(rest),
+        None => Ok(MalList::new().into()),
+    }
+}
+
+fn lazy_range(start: f64, end: Option<f64>, step: f64) -> Mal {
+    LazySeq::new(move || {
+        let done = match end {
+            Some(end) => (step > 0.0 && start >= end) || (step < 0.0 && start <= end),
+            None => false,
+        };
+        if done {
+            Ok(Mal::Nil)
+        } else {
+            Ok(LazySeq::cons(start.into(), lazy_range(start + step, end, step)).into())
+        }
+    }).into()
+}
+
+fn range(args: &mut MalList) -> Result<Mal> {
+    let nums = args.iter().map(|arg| arg.number()).collect::<Result<Vec<_>>>()?;
+    match nums.len() {
+        0 => Ok(lazy_range(0.0, None, 1.0)),
+        1 => Ok(lazy_range(0.0, Some(nums[0]), 1.0)),
+        2 => Ok(lazy_range(nums[0], Some(nums[1]), 1.0)),
+        3 => Ok(lazy_range(nums[0], Some(nums[1]), nums[2])),
+        n => bail!("'range' takes 0 to 3 arguments, found {}", n),
+    }
+}
+
+fn lazy_iterate(func: Mal, value: Mal) -> Mal {
+    let current = value.clone();
+    let rest = LazySeq::new(move || {
+        let next = call1(&func, value.clone())?;
+        Ok(lazy_iterate(func.clone(), next))
+    });
+    LazySeq::cons(current, rest.into()).into()
+}
+
+fn iterate(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("iterate", 2, args)?;
+    let func = args.pop_front().unwrap();
+    let value = args.pop_front().unwrap();
+    Ok(lazy_iterate(func, value))
+}
+
+fn lazy_repeat(value: Mal, times: Option<usize>) -> Mal {
+    LazySeq::new(move || {
+        match times {
+            Some(0) => Ok(Mal::Nil),
+            _ => {
+                let rest = lazy_repeat(value.clone(), times.map(|n| n - 1));
+                Ok(LazySeq::cons(value.clone(), rest).into())
+            }
+        }
+    }).into()
+}
+
+fn repeat(args: &mut MalList) -> Result<Mal> {
+    match args.len() {
+        1 => Ok(lazy_repeat(args.pop_front().unwrap(), None)),
+        2 => {
+            let times = count_arg("repeat", &args[0])?;
+            Ok(lazy_repeat(args.pop_back().unwrap(), Some(times)))
+        }
+        n => bail!("'repeat' takes 1 or 2 arguments, found {}", n),
+    }
+}
+
+fn lazy_cycle(coll: Mal, current: Mal) -> Mal {
+    LazySeq::new(move || {
+        match current.first_rest()? {
+            Some((first, rest)) => Ok(LazySeq::cons(first, lazy_cycle(coll.clone(), rest)).into()),
+            None => {
+                // Start over, unless the collection is empty.
+                match coll.first_rest()? {
+                    Some((first, rest)) => Ok(LazySeq::cons(first, lazy_cycle(coll.clone(), rest)).into()),
+                    None => Ok(Mal::Nil),
+                }
+            }
+        }
+    }).into()
+}
+
+fn cycle(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("cycle", 1, args)?;
+    let coll = args.pop_front().unwrap();
+    Ok(lazy_cycle(coll.clone(), coll))
+}
+
+fn lazy_take(n: usize, coll: Mal) -> Mal {
+    LazySeq::new(move || {
+        if n == 0 {
+            return Ok(Mal::Nil);
+        }
+        match coll.first_rest()? {
+            Some((first, rest)) => Ok(LazySeq::cons(first, lazy_take(n - 1, rest)).into()),
+            None => Ok(Mal::Nil),
+        }
+    }).into()
+}
+
+fn take(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("take", 2, args)?;
+    let n = count_arg("take", &args[0])?;
+    Ok(lazy_take(n, args.pop_back().unwrap()))
+}
+
+fn drop(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("drop", 2, args)?;
+    let n = count_arg("drop", &args[0])?;
+    let coll = args.pop_back().unwrap();
+    Ok(LazySeq::new(move || {
+        let mut seq = coll.clone();
+        for _ in 0..n {
+            match seq.first_rest()? {
+                Some((_, rest)) => seq = rest,
+                None => return Ok(Mal::Nil),
+            }
+        }
+        Ok(seq)
+    }).into())
+}
+
+fn lazy_take_while(pred: Mal, coll: Mal) -> Mal {
+    LazySeq::new(move || {
+        match coll.first_rest()? {
+            Some((first, rest)) => {
+                if call1(&pred, first.clone())?.is_truesy() {
+                    Ok(LazySeq::cons(first, lazy_take_while(pred.clone(), rest)).into())
+                } else {
+                    Ok(Mal::Nil)
+                }
+            }
+            None => Ok(Mal::Nil),
+        }
+    }).into()
+}
+
+fn take_while(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("take-while", 2, args)?;
+    let pred = args.pop_front().unwrap();
+    Ok(lazy_take_while(pred, args.pop_front().unwrap()))
+}
+
+fn doall(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("doall", 1, args)?;
+    let coll = args.pop_front().unwrap();
+    if let Mal::Lazy(_) = coll {
+        coll.seq_items()?;
+    }
+    Ok(coll)
+}
+
+fn lazy_map(func: Mal, coll: Mal) -> Mal {
+    LazySeq::new(move || {
+        match coll.first_rest()? {
+            Some((first, rest)) => {
+                let value = call1(&func, first)?;
+                Ok(LazySeq::cons(value, lazy_map(func.clone(), rest)).into())
+            }
+            None => Ok(Mal::Nil),
+        }
+    }).into()
+}
+
+/// Returns a lazy sequence for lazy input, and a list otherwise.
+fn map(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("map", 2, args)?;
+    let func = args.pop_front().unwrap();
+    let coll = args.pop_front().unwrap();
+    if let Mal::Lazy(_) = coll {
+        return Ok(lazy_map(func, coll));
+    }
+    let mut list = MalList::new();
+    for item in coll.seq_items()? {
+        list.push_back(call1(&func, item)?);
+    }
+    Ok(list.into())
+}
+
+fn lazy_filter(pred: Mal, coll: Mal) -> Mal {
+    LazySeq::new(move || {
+        let mut seq = coll.clone();
+        while let Some((first, rest)) = seq.first_rest()? {
+            if call1(&pred, first.clone())?.is_truesy() {
+                return Ok(LazySeq::cons(first, lazy_filter(pred.clone(), rest)).into());
+            }
+            seq = rest;
+            limits::tick()?;
+        }
+        Ok(Mal::Nil)
+    }).into()
+}
+
+/// Returns a lazy sequence for lazy input, and a list otherwise.
+fn filter(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("filter", 2, args)?;
+    let pred = args.pop_front().unwrap();
+    let coll = args.pop_front().unwrap();
+    if let Mal::Lazy(_) = coll {
+        return Ok(lazy_filter(pred, coll));
+    }
+    let mut list = MalList::new();
+    for item in coll.seq_items()? {
+        if call1(&pred, item.clone())?.is_truesy() {
+            list.push_back(item);
+        }
+    }
+    Ok(list.into())
+}
+
+fn concat(args: &mut MalList) -> Result<Mal> {
+    let mut list = MalList::new();
+    for coll in args.iter() {
+        list.append(coll.seq_items()?);
+    }
+    Ok(list.into())
+}
+
+fn atom(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("atom", 1, args)?;
+    Ok(MalAtom::new(args.pop_front().unwrap()).into())
+}
+
+fn atomp(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("atom?", 1, args)?;
+    Ok(matches!(args[0], Mal::Atom(_)).into())
+}
+
+fn deref(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("deref", 1, args)?;
+    Ok(args[0].atom()?.get())
+}
+
+fn reset(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("reset!", 2, args)?;
+    let value = args.pop_back().unwrap();
+    args[0].atom()?.set(value.clone());
+    Ok(value)
+}
+
+/// Sets the atom to the result of calling the function with its value
+/// and any further arguments.
+fn swap(args: &mut MalList) -> Result<Mal> {
+    if args.len() < 2 {
+        bail!("'swap!' takes 2 or more arguments, found {}", args.len());
+    }
+    let atom = args.pop_front().unwrap();
+    let atom = atom.atom()?;
+    let func = args.pop_front().unwrap();
+    args.push_front(atom.get());
+    let value = eval::call(&func, mem::take(args))?;
+    atom.set(value.clone());
+    Ok(value)
+}
+
+/// Milliseconds since the Unix epoch.
+fn time_ms(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("time-ms", 0, args)?;
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH)
+        .chain_err(|| "'time-ms': the clock is set before 1970")?;
+    Ok((elapsed.as_secs_f64() * 1000.0).floor().into())
+}
+
+/// Asks for the cycles of unreachable atoms to be collected, which happens
+/// once the top-level form being evaluated is done.
+fn gc_(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("gc", 0, args)?;
+    gc::request();
+    Ok(Mal::Nil)
+}
+
+fn gc_stats(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("gc-stats", 0, args)?;
+    let stats = gc::stats();
+    let mut map = MalMap::new();
+    map.insert(Keyword::new("collections"), stats.collections as f64);
+    map.insert(Keyword::new("atoms"), stats.atoms as f64);
+    map.insert(Keyword::new("freed"), stats.freed as f64);
+    map.insert(Keyword::new("total-freed"), stats.total_freed as f64);
+    Ok(map.into())
+}
+
 fn println(args: &mut MalList) -> Result<Mal> {
-    if let Mal::Str(string) = str_(args).unwrap() {
+    if let Mal::Str(string) = str_(args)? {
         println!("{}", string);
         Ok(Mal::Nil)
     } else {
@@ -37,7 +1075,7 @@ fn println(args: &mut MalList) -> Result<Mal> {
 }
 
 fn prn(args: &mut MalList) -> Result<Mal> {
-    if let Mal::Str(string) = pr_str(args).unwrap() {
+    if let Mal::Str(string) = pr_str(args)? {
         println!("{}", string);
         Ok(Mal::Nil)
     } else {
@@ -50,9 +1088,9 @@ fn str_(args: &mut MalList) -> Result<Mal> {
         return Ok(String::from("").into());
     }
     let first = args.pop_front().unwrap();
-    let mut string = printer::pr_str(&first, false);
-    for arg in args.drain(..) {
-        printer::pr_str_into(&arg, &mut string, false);
+    let mut string = printer::pr_str(&first, false)?;
+    for arg in args.iter() {
+        printer::pr_str_into(arg, &mut string, false)?;
     }
     Ok(string.into())
 }
@@ -62,14 +1100,19 @@ fn pr_str(args: &mut MalList) -> Result<Mal> {
         return Ok(String::from("").into());
     }
     let first = args.pop_front().unwrap();
-    let mut string = printer::pr_str(&first, true);
-    for arg in args.drain(..) {
+    let mut string = printer::pr_str(&first, true)?;
+    for arg in args.iter() {
         string.push(' ');
-        printer::pr_str_into(&arg, &mut string, true);
+        printer::pr_str_into(arg, &mut string, true)?;
     }
     Ok(string.into())
 }
 
+fn read_string(args: &mut MalList) -> Result<Mal> {
+    assert_nargs("read-string", 1, args)?;
+    reader::read_str(args[0].string()?)
+}
+
 fn ge(args: &mut MalList) -> Result<Mal> {
     if args.len() < 2 {
         bail!("'>=' takes 2 or more arguments, got {}", args.len());
@@ -136,7 +1179,7 @@ fn eq(args: &mut MalList) -> Result<Mal> {
         bail!("'=' takes 2 or more arguments, got {}", args.len());
     }
     let first = args.pop_front().unwrap();
-    Ok(args.drain(..).all(|arg| arg == first).into())
+    Ok(args.iter().all(|arg| *arg == first).into())
 }
 
 fn assert_nargs(name: &str, nargs: usize, args: &MalList) -> Result<()> {
@@ -153,7 +1196,10 @@ fn emptyp(args: &mut MalList) -> Result<Mal> {
         Mal::List(ref list) => Ok(list.is_empty().into()),
         Mal::Arr(ref arr) => Ok(arr.is_empty().into()),
         Mal::Map(ref map) => Ok(map.is_empty().into()),
-        ref other => bail!("'empty?' takes a collection type, found {}", other.type_name()),
+        Mal::Set(ref set) => Ok(set.is_empty().into()),
+        Mal::Str(ref string) => Ok(string.is_empty().into()),
+        Mal::Lazy(ref lazy) => Ok(lazy.step()?.is_none().into()),
+        ref other => bail!("'empty?' takes a collection type or string, found {}", other.type_name()),
     }
 }
 
@@ -164,8 +1210,11 @@ fn count(args: &mut MalList) -> Result<Mal> {
         Mal::List(ref list) => Ok((list.len() as f64).into()),
         Mal::Arr(ref arr) => Ok((arr.len() as f64).into()),
         Mal::Map(ref map) => Ok((map.len() as f64).into()),
+        Mal::Set(ref set) => Ok((set.len() as f64).into()),
+        Mal::Str(ref string) => Ok((string.chars().count() as f64).into()),
+        Mal::Lazy(_) => Ok((arg.seq_items()?.len() as f64).into()),
         Mal::Nil => Ok(0.0f64.into()),
-        ref other => bail!("'count' takes a collection type (or nil), found {}", other.type_name()),
+        ref other => bail!("'count' takes a collection type or string (or nil), found {}", other.type_name()),
     }
 }
 
@@ -0,0 +1,197 @@
+//! Binding of destructuring patterns, as used by `let*`, `fn*`, `def!` and
+//! `loop`, and the stricter patterns of `match`.
+//!
+//! The patterns are checked and given slots by the analyzer; this binds
+//! values to them in a frame.
+use types::{Mal, MalList, MalMap};
+use ast::{Pattern, MapEntry, MatchPattern};
+use eval::{Frame, eval_value};
+use printer;
+use errors::*;
+
+fn pattern_str(pattern: &Mal) -> String {
+    printer::pr_str(pattern, true).unwrap_or_else(|_| pattern.type_name().into())
+}
+
+/// Binds the value to the pattern in the given frame.
+pub fn bind(pattern: &Pattern, value: Mal, frame: &mut Frame) -> Result<()> {
+    match *pattern {
+        Pattern::Slot(slot) => {
+            frame.slots[slot] = value;
+            Ok(())
+        }
+        Pattern::Seq { ref items, ref rest, whole, ref source } => {
+            match value {
+                Mal::List(_) | Mal::Arr(_) | Mal::Lazy(_) | Mal::Str(_) | Mal::Bytes(_) | Mal::Nil => {
+                    bind_seq(items, rest.as_deref(), whole, value, frame)
+                }
+                ref other => bail!("Cannot destructure {} with pattern {}",
+                    other.type_name(), pattern_str(source)),
+            }
+        }
+        Pattern::Map { ref entries, whole, ref source } => {
+            match value {
+                Mal::Map(_) | Mal::Nil => bind_map(entries, whole, value, frame),
+                ref other => bail!("Cannot destructure {} with pattern {}",
+                    other.type_name(), pattern_str(source)),
+            }
+        }
+    }
+}
+
+/// Binds the arguments of a call to the parameters of a closure. Missing
+/// arguments are bound to nil.
+pub fn bind_args(params: &[Pattern], rest: Option<&Pattern>, mut args: Vec<Mal>,
+        frame: &mut Frame) -> Result<()> {
+    let rest_args = if args.len() > params.len() {
+        args.split_off(params.len())
+    } else {
+        Vec::new()
+    };
+    let mut args = args.into_iter();
+    for param in params {
+        bind(param, args.next().unwrap_or(Mal::Nil), frame)?;
+    }
+    if let Some(rest) = rest {
+        bind(rest, rest_args.into_iter().collect::<MalList>().into(), frame)?;
+    }
+    Ok(())
+}
+
+/// Binds the items of a sequential value to a sequential pattern. Missing
+/// items are bound to nil.
+fn bind_seq(items: &[Pattern], rest: Option<&Pattern>, whole: Option<usize>, value: Mal,
+        frame: &mut Frame) -> Result<()> {
+    if let Some(slot) = whole {
+        frame.slots[slot] = value.clone();
+    }
+    // Lists and arrays are indexed instead of walked.
+    let indexed = match value {
+        Mal::List(ref list) => Some(list.items.clone()),
+        Mal::Arr(ref arr) => Some(arr.items.clone()),
+        _ => None,
+    };
+    if let Some(values) = indexed {
+        for (i, item) in items.iter().enumerate() {
+            bind(item, values.get(i).cloned().unwrap_or(Mal::Nil), frame)?;
+        }
+        if let Some(rest) = rest {
+            let skip = items.len().min(values.len());
+            bind(rest, MalList::from(values.skip(skip)).into(), frame)?;
+        }
+        return Ok(());
+    }
+    let mut seq = value;
+    for item in items {
+        let next = match seq.first_rest()? {
+            Some((first, rest)) => {
+                seq = rest;
+                first
+            }
+            None => Mal::Nil,
+        };
+        bind(item, next, frame)?;
+    }
+    if let Some(rest) = rest {
+        let rest_value = match seq {
+            Mal::Nil => MalList::new().into(),
+            other => other,
+        };
+        bind(rest, rest_value, frame)?;
+    }
+    Ok(())
+}
+
+fn bind_map(entries: &[MapEntry], whole: Option<usize>, value: Mal, frame: &mut Frame) -> Result<()> {
+    let empty = MalMap::new();
+    let target = match value {
+        Mal::Map(ref target) => target,
+        _ => &empty,
+    };
+    for entry in entries {
+        let found = match target.get(&entry.key) {
+            Some(found) => found.clone(),
+            None => match entry.default {
+                Some(ref default) => eval_value(default, frame)?,
+                None => Mal::Nil,
+            },
+        };
+        bind(&entry.pattern, found, frame)?;
+    }
+    if let Some(slot) = whole {
+        frame.slots[slot] = value;
+    }
+    Ok(())
+}
+
+/// Matches a value against a `match` pattern, binding its locals as it
+/// goes. Unlike destructuring, a value of the wrong shape does not match.
+///
+/// Symbols match anything (`_` without binding it), vector and list
+/// patterns match sequences of the same length (or longer, with `& rest`),
+/// map patterns match maps with all of the given keys, and any other value
+/// matches an equal value.
+pub fn match_pattern(pattern: &MatchPattern, value: &Mal, frame: &mut Frame) -> Result<bool> {
+    match *pattern {
+        MatchPattern::Any => Ok(true),
+        MatchPattern::Bind(slot) => {
+            frame.slots[slot] = value.clone();
+            Ok(true)
+        }
+        MatchPattern::Seq { ref items, ref rest, whole } => {
+            match_seq(items, rest.as_deref(), whole, value, frame)
+        }
+        MatchPattern::Map(ref entries) => {
+            let target = match *value {
+                Mal::Map(ref target) => target,
+                _ => return Ok(false),
+            };
+            for (key, item) in entries {
+                match target.get(key) {
+                    Some(found) => {
+                        if ! match_pattern(item, found, frame)? {
+                            return Ok(false);
+                        }
+                    }
+                    None => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+        MatchPattern::Literal(ref literal) => Ok(literal == value),
+    }
+}
+
+fn match_seq(items: &[MatchPattern], rest: Option<&MatchPattern>, whole: Option<usize>,
+        value: &Mal, frame: &mut Frame) -> Result<bool> {
+    match *value {
+        Mal::List(_) | Mal::Arr(_) | Mal::Lazy(_) => {}
+        _ => return Ok(false),
+    }
+    if let Some(slot) = whole {
+        frame.slots[slot] = value.clone();
+    }
+    let mut seq = value.clone();
+    for item in items {
+        match seq.first_rest()? {
+            Some((first, rest)) => {
+                if ! match_pattern(item, &first, frame)? {
+                    return Ok(false);
+                }
+                seq = rest;
+            }
+            None => return Ok(false),
+        }
+    }
+    match rest {
+        Some(rest) => {
+            let rest_value = match seq {
+                Mal::Arr(arr) => MalList::from(arr.items.as_ref().clone()).into(),
+                other => other,
+            };
+            match_pattern(rest, &rest_value, frame)
+        }
+        // Without a rest pattern, there can't be items left over.
+        None => Ok(seq.first_rest()?.is_none()),
+    }
+}
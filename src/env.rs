@@ -1,70 +1,265 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
 use types::{Mal, NativeFunc, MalFunc, Symbol};
+use modules::Modules;
+use interpreter::Engine;
+use gc;
 use errors::*;
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum EnvChange {
-    /// A new scope was entered
-    NewScopeEntered,
-    /// A new binding was added
-    BindingAdded(Symbol),
-    /// The binding of symbol was replaced, and the old value was 'Mal'
-    BindingReplaced(Symbol, Mal)
+/// The namespace of the native functions.
+pub const CORE_NS: &str = "core";
+/// The namespace that evaluation starts in.
+pub const USER_NS: &str = "user";
+
+/// A global variable, like `user/x`. Code refers to the var itself once it
+/// is analyzed, so it sees later definitions, and may refer to a global
+/// before it is defined.
+#[derive(Clone)]
+pub struct Var {
+    inner: Rc<VarInner>,
+}
+
+struct VarInner {
+    name: Symbol,
+    value: RefCell<Option<Mal>>,
+}
+
+impl Var {
+    fn new(name: Symbol) -> Var {
+        Var { inner: Rc::new(VarInner { name, value: RefCell::new(None) }) }
+    }
+
+    /// The qualified name of the var.
+    pub fn name(&self) -> &Symbol {
+        &self.inner.name
+    }
+
+    /// The value of the var, unless it is not defined yet.
+    #[inline]
+    pub fn get(&self) -> Option<Mal> {
+        self.inner.value.borrow().clone()
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.inner.value.borrow().is_some()
+    }
+
+    fn replace(&self, value: Option<Mal>) -> Option<Mal> {
+        self.inner.value.replace(value)
+    }
+}
+
+impl fmt::Debug for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Var {{ {} }}", self.name().text())
+    }
+}
+
+impl PartialEq for Var {
+    fn eq(&self, other: &Var) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Eq for Var {}
+
+/// The global state of an interpreter: the vars of every namespace, the
+/// current namespace and the loaded modules.
+///
+/// Cloning an `Env` gives another handle to the same globals, which is how
+/// analyzed code keeps hold of them. Locals are not kept here; they live
+/// in the frames of the evaluator.
+#[derive(Clone)]
 pub struct Env {
-    map: HashMap<Symbol, Mal>,
-    history: Vec<EnvChange>,
+    inner: Rc<Globals>,
+}
+
+pub(crate) struct Globals {
+    /// Vars by their qualified name, eg. `user/x`.
+    vars: RefCell<HashMap<Symbol, Var>>,
+    ns: RefCell<Symbol>,
+    /// Namespace aliases, keyed by the namespace they were made in and the alias.
+    aliases: RefCell<HashMap<(Symbol, Symbol), Symbol>>,
+    modules: Modules,
+    engine: Cell<Engine>,
+}
+
+/// Splits a qualified name like `str/join` into its namespace and name.
+pub fn split_qualified(text: &str) -> Option<(&str, &str)> {
+    match text.find('/') {
+        Some(i) if i > 0 && i + 1 < text.len() => Some((&text[..i], &text[i + 1..])),
+        _ => None,
+    }
+}
+
+fn qualify(ns: &str, name: &str) -> Symbol {
+    Symbol::new(format!("{}/{}", ns, name))
 }
 
 impl Env {
     pub fn new() -> Env {
-        Env { map: HashMap::new(), history: Vec::new() }
-    }
-    
-    pub fn with_new_scope<F, R>(&mut self, mut func: F) -> R where F: FnMut(&mut Env) -> R {
-        use self::EnvChange::*;
-        self.history.push(NewScopeEntered);
-        let res = func(self);
-        // Revert back to previous state.
-        loop {
-            match self.history.pop().expect("Broken env invariant!") {
-                NewScopeEntered => break,
-                BindingAdded(ident) => {
-                    self.map.remove(&ident).expect("Broken env invariant");
-                }
-                BindingReplaced(sym, val) => {
-                    self.map.insert(sym, val);
+        let inner = Rc::new(Globals {
+            vars: RefCell::new(HashMap::new()),
+            ns: RefCell::new(Symbol::new(USER_NS)),
+            aliases: RefCell::new(HashMap::new()),
+            modules: Modules::new(),
+            engine: Cell::new(Engine::default()),
+        });
+        gc::register_env(Rc::downgrade(&inner));
+        Env { inner }
+    }
+
+    /// Returns another handle to the same globals.
+    pub fn fork(&self) -> Env {
+        self.clone()
+    }
+
+    /// The current namespace.
+    pub fn ns(&self) -> Symbol {
+        self.inner.ns.borrow().clone()
+    }
+
+    pub fn set_ns(&self, ns: Symbol) {
+        *self.inner.ns.borrow_mut() = ns;
+    }
+
+    pub fn modules(&self) -> &Modules {
+        &self.inner.modules
+    }
+
+    /// The engine that runs top-level forms.
+    pub fn engine(&self) -> Engine {
+        self.inner.engine.get()
+    }
+
+    pub fn set_engine(&self, engine: Engine) {
+        self.inner.engine.set(engine);
+    }
+
+    /// Makes `alias/name` refer to `ns/name` in the current namespace.
+    pub fn add_alias(&self, alias: Symbol, ns: Symbol) {
+        self.inner.aliases.borrow_mut().insert((self.ns(), alias), ns);
+    }
+
+    /// The qualified name a symbol refers to in the current namespace,
+    /// without looking at native functions.
+    fn global_name(&self, ident: &Symbol) -> Symbol {
+        match split_qualified(ident.text()) {
+            Some((ns, name)) => {
+                let key = (self.ns(), Symbol::new(ns));
+                match self.inner.aliases.borrow().get(&key) {
+                    Some(target) => qualify(target.text(), name),
+                    None => ident.clone(),
                 }
             }
+            None => qualify(self.ns().text(), ident.text()),
+        }
+    }
+
+    /// Finds the var a symbol refers to: a global of the current namespace
+    /// (or of the namespace a qualified symbol names), then a native function.
+    pub fn resolve(&self, ident: &Symbol) -> Option<Var> {
+        let vars = self.inner.vars.borrow();
+        if let Some(var) = vars.get(&self.global_name(ident)) {
+            return Some(var.clone());
+        }
+        vars.get(&qualify(CORE_NS, ident.text())).cloned()
+    }
+
+    /// Like `resolve`, but makes a var to be defined later if there is none.
+    pub fn resolve_or_add(&self, ident: &Symbol) -> Var {
+        if let Some(var) = self.resolve(ident) {
+            return var;
         }
-        res
+        self.intern(self.global_name(ident))
+    }
+
+    /// Returns the var with the qualified name, adding it if needed.
+    fn intern(&self, name: Symbol) -> Var {
+        self.inner.vars.borrow_mut()
+            .entry(name.clone())
+            .or_insert_with(|| Var::new(name))
+            .clone()
     }
-    
-    /// Returns a clone of the value associated with the symbol in this environment.
+
+    /// Returns a clone of the value of the global the symbol refers to.
     pub fn get(&self, ident: &Symbol) -> Result<Mal> {
-        if let Some(mal) = self.map.get(ident) {
-            Ok(mal.clone())
-        } else {
-            bail!("Unknown variable: '{}'", ident.text());
+        match self.resolve(ident).and_then(|var| var.get()) {
+            Some(value) => Ok(value),
+            None => bail!("Unknown variable: '{}'", ident.text()),
         }
     }
-    
+
+    /// Binds a global in the current namespace, unless the symbol is
+    /// already qualified.
     pub fn set<K: Into<Symbol>, V: Into<Mal>>(&mut self, ident: K, value: V) {
-        let symbol = ident.into();
-        match self.map.insert(symbol.clone(), value.into()) {
-            None => self.history.push(EnvChange::BindingAdded(symbol)),
-            Some(old_value) => self.history.push(EnvChange::BindingReplaced(symbol, old_value))
+        let var = self.define_var(&ident.into());
+        self.set_var(&var, value.into());
+    }
+
+    /// Returns the var that `def!` of the symbol binds.
+    pub fn define_var(&self, ident: &Symbol) -> Var {
+        if split_qualified(ident.text()).is_some() {
+            self.intern(ident.clone())
+        } else {
+            self.intern(qualify(self.ns().text(), ident.text()))
         }
     }
-    
+
+    /// Sets the value of a var.
+    pub fn set_var(&self, var: &Var, value: Mal) {
+        var.replace(Some(value));
+    }
+
+    /// Unbinds every var. Functions refer to the vars they use, and vars
+    /// hold functions, so the globals are only freed once this is done.
+    pub fn clear_vars(&self) {
+        let values: Vec<Mal> = self.inner.vars.borrow().values()
+            .filter_map(|var| var.replace(None))
+            .collect();
+        // The values are dropped once nothing is borrowed.
+        drop(values);
+    }
+
     pub fn add_native_func(&mut self, name: &'static str, func: NativeFunc) -> Result<()> {
-        let symbol = Symbol::new(name);
-        if self.map.contains_key(&symbol) {
+        let var = self.intern(qualify(CORE_NS, name));
+        if var.is_bound() {
             bail!("Native function '{}' declared twice!", name);
         }
-        self.map.insert(symbol, MalFunc::Native(name, func).into());
+        var.replace(Some(MalFunc::Native(name, func).into()));
         Ok(())
     }
 }
+
+impl Globals {
+    /// Calls the function with the value of every var.
+    pub(crate) fn for_each_value<F: FnMut(&Mal)>(&self, mut func: F) {
+        for var in self.vars.borrow().values() {
+            if let Some(ref value) = *var.inner.value.borrow() {
+                func(value);
+            }
+        }
+    }
+}
+
+impl Default for Env {
+    fn default() -> Env {
+        Env::new()
+    }
+}
+
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Env {{ ns: {}, vars: {} }}", self.ns().text(), self.inner.vars.borrow().len())
+    }
+}
+
+impl PartialEq for Env {
+    fn eq(&self, other: &Env) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for Env {}
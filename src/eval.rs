@@ -1,253 +1,358 @@
-use mal::{self, Mal, MalList, Env, MalFunc, Symbol};
-use mal::errors::*;
-use std::collections::VecDeque;
-
-/// Resolves symbols to their environment values.
-fn eval_ast(expr: &mut Mal, env: &mut Env) -> mal::Result<()> {
-    use mal::Mal::*;
-    let mut new_val = None;
-    match *expr {
-        Sym(ref ident) => {
-            new_val = Some(env.get(&ident)?);
-        },
-        Arr(ref mut arr) => {
-            for item in arr.iter_mut() {
-                env.with_new_scope(|env| {
-                    eval(item, env)
-                })?;
-            }
-        }
-        Map(ref mut map) => {
-            for (_, item) in map.iter_mut() {
-                env.with_new_scope(|env| {
-                    eval(item, env)
-                })?;
-            }
-        }
-        List(_) => {
-            unreachable!();
-            //eval_list(list, env)?;
-        }
-        _ => {},
-    }
-    if let Some(val) = new_val {
-        *expr = val;
-    }
-    Ok(())
+//! Evaluation of analyzed code.
+//!
+//! Forms are first turned into nodes by the analyzer, which are then either
+//! walked here or compiled for the `vm`, depending on the engine of the
+//! environment. Both run against a frame holding the locals of the running
+//! function. Calls in
+//! tail position, and `recur`, return to the loop of the function they
+//! leave instead of growing the stack.
+use std::rc::Rc;
+use types::{Mal, MalList, MalArr, MalMap, MalSet, MalFunc, NativeFunc, Closure, Symbol, LazySeq};
+use ast::{Node, Lambda, LambdaArity, Body, Capture, RequireSpec};
+use env::Env;
+use interpreter::Engine;
+use analyzer;
+use vm;
+use destructure;
+use modules;
+use limits;
+use printer;
+use errors::*;
+
+/// The locals of a running closure or top-level form.
+pub struct Frame<'a> {
+    pub slots: Vec<Mal>,
+    closure: Option<&'a Rc<Closure>>,
 }
 
-fn eval_list(list: &mut MalList, env: &mut Env) -> mal::Result<()> {
-    for item in list.iter_mut() {
-        // List members should not share definitions.
-        env.with_new_scope(|env| {
-            eval(item, env)
-        })?;
+impl<'a> Frame<'a> {
+    pub(crate) fn closure(&self) -> &'a Rc<Closure> {
+        self.closure.expect("Upvalue used outside of a closure")
     }
-    Ok(())
 }
 
-/// Evaluates list forms.
-pub fn eval(expr: &mut Mal, env: &mut Env) -> mal::Result<()> {
-    let mut new_val = None;
-    match *expr {
-        Mal::List(ref mut list) => {
-            if ! list.is_empty() {
-                new_val = Some(apply(list, env)?);
+/// Where evaluation goes after a node.
+pub(crate) enum Flow {
+    Value(Mal),
+    /// Call the closure from the loop of the function being left.
+    TailCall(Rc<Closure>, Vec<Mal>),
+    /// Jump back to the start of the innermost loop or function body.
+    Recur(Vec<Mal>),
+}
+
+/// Evaluates a form and returns its value. The form itself is left as it
+/// is, and the analyzed code of the functions it makes is shared by every
+/// call of them.
+pub fn eval(form: &Mal, env: &Env) -> Result<Mal> {
+    // The forms of a top-level `do` are analyzed one at a time, so that
+    // each sees the definitions and namespace changes before it.
+    if let Mal::List(ref list) = *form {
+        if list.front() == Some(&Symbol::new("do").into()) {
+            let mut value = Mal::Nil;
+            for form in list.iter().skip(1) {
+                value = eval(form, env)?;
             }
+            return Ok(value);
         }
-        _ => eval_ast(expr, env)?,
     }
-    if let Some(val) = new_val {
-        *expr = val;
+    let (node, nslots) = analyzer::analyze(form, env)?;
+    let mut frame = Frame { slots: vec![Mal::Nil; nslots], closure: None };
+    match env.engine() {
+        Engine::Tree => eval_value(&node, &mut frame),
+        Engine::Vm => {
+            let chunk = vm::compile(node);
+            finish(vm::run(&chunk, &mut frame)?)
+        }
     }
-    Ok(())
 }
 
-fn assert_arg_len(name: &str, nargs: usize, args: &MalList) -> mal::Result<()> {
-    if args.len() != nargs {
-        bail!("'{}' takes {} arguments, found {}", name, nargs, args.len());
+/// Evaluates a node that isn't in tail position.
+pub(crate) fn eval_value(node: &Node, frame: &mut Frame) -> Result<Mal> {
+    finish(eval_node(node, frame)?)
+}
+
+/// Makes the call that is left to make, if any.
+fn finish(flow: Flow) -> Result<Mal> {
+    match flow {
+        Flow::Value(value) => Ok(value),
+        Flow::TailCall(closure, args) => call_closure(closure, args),
+        Flow::Recur(_) => bail!("recur: Not inside a loop or fn*"),
     }
-    Ok(())
 }
 
-/// Evaluates the expression inside the given list.
-fn apply(list: &mut MalList, env: &mut Env) -> mal::Result<Mal> {
-    let mut first = list.pop_front().unwrap();
-    match first {
-        Mal::Sym(sym) => {
-            apply_symbol(sym, list, env)
-        }
-        _ => {
-            apply_function(&mut first, list, env)
-        }
+fn eval_values(nodes: &[Node], frame: &mut Frame) -> Result<Vec<Mal>> {
+    let mut values = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        values.push(eval_value(node, frame)?);
     }
+    Ok(values)
 }
 
-/// Resolves a list starting with a symbol to either a special form,
-/// or a function that is called.
-fn apply_symbol(symbol: Symbol, list: &mut MalList, env: &mut Env) -> mal::Result<Mal> {
-    match symbol.text() {
-        "def!" => {
-            assert_arg_len("def!", 2, list)?;
-            let sym = list.pop_front().unwrap().symbol()
-                .chain_err(|| "def!: Invalid first argument")?;
-            let mut val = list.pop_front().unwrap();
-            eval(&mut val, env)?;
-            // Make closures 'specially' able to refer to what they're bound to.
-            if let Mal::Fn(MalFunc::Closure(args, env, body)) = val {
-                val = MalFunc::NamedClosure(sym.clone(), args, env, body).into();
+fn eval_node(node: &Node, frame: &mut Frame) -> Result<Flow> {
+    let value = match *node {
+        Node::Const(ref value) => value.clone(),
+        Node::Local(slot) => frame.slots[slot].clone(),
+        Node::Upvalue(i) => frame.closure().captures[i].clone(),
+        Node::SelfRef => MalFunc::Closure(frame.closure().clone()).into(),
+        Node::Global(ref var, ref sym) => {
+            match var.get() {
+                Some(value) => value,
+                None => bail!("Unknown variable: '{}'", sym.text()),
             }
-            env.set(sym, val.clone());
-            Ok(val)
-        }
-        "let*" => {
-            assert_arg_len("let*", 2, list)?;
-            let mut value = list.pop_front().unwrap();
-            let bindings = value.as_list_or_array()
-                .chain_err(|| "let*: Invalid set of bindings")?;
-            if (bindings.len() % 2) != 0 {
-                bail!("let*: odd number of elements in binding list");
+        }
+        Node::Vector(ref nodes) => eval_values(nodes, frame)?.into_iter().collect::<MalArr>().into(),
+        Node::Map(ref entries) => {
+            let mut map = MalMap::new();
+            for (key, value) in entries {
+                let key = eval_value(key, frame)?;
+                map.insert(key, eval_value(value, frame)?);
             }
-            
-            env.with_new_scope(|env| {
-                while ! bindings.is_empty() {
-                    let sym = bindings.pop_front().unwrap().symbol()
-                        .chain_err(|| "let*: Invalid binding variable")?;
-                    let mut val = bindings.pop_front().unwrap();
-                    eval(&mut val, env)?;
-                    env.set(sym, val);
-                }
-                let mut expr = list.pop_front().unwrap();
-                eval(&mut expr, env)?;
-                Ok(expr)
-            })
-        }
-        "fn*" => {
-            assert_arg_len("fn*", 2, list)?;
-            let mut args = list.pop_front().unwrap();
-            let arg_ref = args.as_list_or_array()
-                .chain_err(|| "fn*: Invalid argument list")?;
-            let mut arg_list = VecDeque::new();
-            let mut next_is_vararg = false;
-            let mut has_vararg = false;
-            for arg in arg_ref.drain(..) {
-                let sym = arg.symbol().chain_err(|| "fn*: Invalid argument list")?;
-                if sym.text() == "&" {
-                    if has_vararg {
-                        bail!("fn*: & (vararg operator) used twice!");
-                    } else {
-                        has_vararg = true;
-                        next_is_vararg = true;
-                    }
-                } else {
-                    if next_is_vararg {
-                        next_is_vararg = false;
-                    } else if has_vararg {
-                        bail!("fn*: Got more than one argument after '&'");
+            map.into()
+        }
+        Node::Set(ref nodes) => eval_values(nodes, frame)?.into_iter().collect::<MalSet>().into(),
+        Node::If(ref condition, ref then, ref otherwise) => {
+            if eval_value(condition, frame)?.is_truesy() {
+                return eval_node(then, frame);
+            }
+            match *otherwise {
+                Some(ref otherwise) => return eval_node(otherwise, frame),
+                None => Mal::Nil,
+            }
+        }
+        Node::Do(ref nodes) => {
+            let (last, init) = nodes.split_last().expect("Empty do node");
+            for node in init {
+                eval_value(node, frame)?;
+            }
+            return eval_node(last, frame);
+        }
+        Node::Let(ref bindings, ref body) => {
+            for (pattern, node) in bindings {
+                let value = eval_value(node, frame)?;
+                destructure::bind(pattern, value, frame)
+                    .chain_err(|| "let*: Invalid binding")?;
+            }
+            return eval_node(body, frame);
+        }
+        Node::Loop(ref bindings, ref body) => {
+            for (pattern, node) in bindings {
+                let value = eval_value(node, frame)?;
+                destructure::bind(pattern, value, frame)
+                    .chain_err(|| "loop: Invalid binding")?;
+            }
+            loop {
+                match eval_node(body, frame)? {
+                    Flow::Recur(values) => {
+                        limits::step()?;
+                        for ((pattern, _), value) in bindings.iter().zip(values) {
+                            destructure::bind(pattern, value, frame)
+                                .chain_err(|| "recur: Invalid arguments")?;
+                        }
                     }
+                    flow => return Ok(flow),
                 }
-                arg_list.push_back(sym);
-
             }
-            let body = list.pop_front().unwrap();
-            Ok(Mal::Fn(MalFunc::Closure(arg_list, env.clone(), Box::new(body))))
-        }
-        "do" => { // TODO: Is 'do' actually a new scope? Apparently not.
-            let mut res = Mal::Nil;
-            for arg in list.drain(..) {
-                res = arg;
-                eval(&mut res, env)?;
+        }
+        Node::Recur(ref nodes) => return Ok(Flow::Recur(eval_values(nodes, frame)?)),
+        Node::Fn(ref lambda) => MalFunc::Closure(make_closure(lambda, frame)).into(),
+        Node::LazySeq(ref lambda) => {
+            let closure = make_closure(lambda, frame);
+            LazySeq::new(move || call_closure(closure.clone(), Vec::new())).into()
+        }
+        Node::Def(ref env, ref var, ref node) => {
+            let value = eval_value(node, frame)?;
+            env.set_var(var, value.clone());
+            value
+        }
+        Node::DefPattern(ref env, ref pattern, ref vars, ref node) => {
+            let value = eval_value(node, frame)?;
+            destructure::bind(pattern, value.clone(), frame)
+                .chain_err(|| "def!: Invalid binding")?;
+            for (var, slot) in vars {
+                env.set_var(var, frame.slots[*slot].clone());
             }
-            Ok(res)
+            value
         }
-        "if" => {
-            if ! (list.len() == 2 || list.len() == 3) {
-                bail!("'if' takes 2 or 3 arguments, found {}", list.len());
+        Node::DefLocal(ref pattern, ref node) => {
+            let value = eval_value(node, frame)?;
+            destructure::bind(pattern, value.clone(), frame)
+                .chain_err(|| "def!: Invalid binding")?;
+            value
+        }
+        Node::Match(ref node, ref clauses) => {
+            let value = eval_value(node, frame)?;
+            for clause in clauses {
+                if ! destructure::match_pattern(&clause.pattern, &value, frame)? {
+                    continue;
+                }
+                if let Some(ref guard) = clause.guard {
+                    if ! eval_value(guard, frame)?.is_truesy() {
+                        continue;
+                    }
+                }
+                return eval_node(&clause.body, frame);
             }
-            let has_else = list.len() == 3;
-            let mut condition = list.pop_front().unwrap();
-            eval(&mut condition, env)?;
-            
-            let mut if_body = list.pop_front().unwrap();
-            if condition.is_truesy() {
-                eval(&mut if_body, env)?;
-                Ok(if_body)
-            } else {
-                if has_else {
-                    let mut else_body = list.pop_front().unwrap();
-                    eval(&mut else_body, env)?;
-                    Ok(else_body)
-                } else {
-                    Ok(Mal::Nil)
+            bail!("match: No clause matched {}", printer::pr_str(&value, true)?);
+        }
+        Node::Ns(ref env, ref ns, ref specs) => {
+            env.set_ns(ns.clone());
+            require_all(specs, env)?;
+            Mal::Nil
+        }
+        Node::InNs(ref env, ref ns) => {
+            env.set_ns(ns.clone());
+            Mal::Nil
+        }
+        Node::Require(ref env, ref specs) => {
+            require_all(specs, env)?;
+            Mal::Nil
+        }
+        Node::LoadFile(ref env, ref path) => {
+            let path = eval_value(path, frame)?;
+            modules::load_file(path.string()?, env)?;
+            Mal::Nil
+        }
+        Node::Call { ref func, ref args, tail } => {
+            let func = eval_value(func, frame)?;
+            let args = eval_values(args, frame)?;
+            match func {
+                Mal::Fn(MalFunc::Closure(closure)) if tail => {
+                    return Ok(Flow::TailCall(closure, args));
                 }
+                func => apply(func, args)?,
             }
         }
-        _ => {
-            let mut func = env.get(&symbol)?;
-            apply_function(&mut func, list, env)
+    };
+    Ok(Flow::Value(value))
+}
+
+fn not_a_function(value: &Mal) -> Error {
+    ErrorKind::TypeError {
+        expected: "function".into(),
+        got: value.type_name().into(),
+    }.into()
+}
+
+fn require_all(specs: &[RequireSpec], env: &Env) -> Result<()> {
+    for spec in specs {
+        modules::require(&spec.ns, env)?;
+        if let Some(ref alias) = spec.alias {
+            env.add_alias(alias.clone(), spec.ns.clone());
         }
     }
+    Ok(())
 }
 
-/// Resolves the given value to a function and calls it.
-fn apply_function(func: &mut Mal, args: &mut MalList, env: &mut Env) -> mal::Result<Mal> {
-    use mal::types::MalFunc::*;
-    eval(func, env)?;
-    let function = func.as_function()?;
-    env.with_new_scope(|env| {
-        // Evaluate the argument values in the outer env.
-        eval_list(args, env)
-    })?;
-    match *function {
-        // NOTE: This should've been cloned at env.get, so safe to modify.
-        Closure(ref mut arg_names, ref mut closure_env, ref mut body) => {
-            apply_closure(None, arg_names, args, closure_env, body)
-        }
-        NamedClosure(ref mut name, ref mut arg_names, 
-                ref mut closure_env, ref mut body) => {
-            let self_reference = NamedClosure(name.clone(), arg_names.clone(), 
-                closure_env.clone(), body.clone());
-            closure_env.set(name.clone(), self_reference);
-            apply_closure(Some(name.text()), arg_names, args, closure_env, body)
-        }
-        Native(_, ref mut func) => {
-            func(args)
+/// Makes a closure of the lambda, capturing its upvalues from the frame.
+pub(crate) fn make_closure(lambda: &Rc<Lambda>, frame: &Frame) -> Rc<Closure> {
+    let captures = lambda.captures.iter().map(|capture| {
+        match *capture {
+            Capture::Local(slot) => frame.slots[slot].clone(),
+            Capture::Upvalue(i) => frame.closure().captures[i].clone(),
+            Capture::SelfRef => MalFunc::Closure(frame.closure().clone()).into(),
         }
+    }).collect();
+    Rc::new(Closure { lambda: lambda.clone(), captures })
+}
+
+/// Calls a function value with already evaluated arguments, eg. from a
+/// native function like 'map'.
+pub fn call(func: &Mal, mut args: MalList) -> Result<Mal> {
+    match *func {
+        Mal::Fn(MalFunc::Closure(ref closure)) => {
+            call_closure(closure.clone(), args.iter().cloned().collect())
+        }
+        Mal::Fn(MalFunc::Native(_, native)) => call_native(native, &mut args),
+        ref other => Err(not_a_function(other)),
     }
 }
 
-fn apply_closure(name: Option<&str>, arg_names: &mut VecDeque<Symbol>, args: &mut MalList, 
-        closure_env: &mut Env, body: &mut Mal) -> mal::Result<Mal> {
-    
-    let takes_varargs = arg_names.iter().any(|arg| arg.text() == "&");
-    if ! takes_varargs {
-        assert_arg_len(name.unwrap_or("#<function>"), arg_names.len(), args)?;
-    } else {
-        let nargs = arg_names.len() - 2;
-        if args.len() < nargs {
-            bail!("'{}' takes {} or more arguments, found {}!", 
-                name.unwrap_or("#<function>"), nargs, args.len());
+/// Calls a function value with evaluated arguments.
+pub(crate) fn apply(func: Mal, args: Vec<Mal>) -> Result<Mal> {
+    match func {
+        Mal::Fn(MalFunc::Closure(closure)) => call_closure(closure, args),
+        Mal::Fn(MalFunc::Native(_, native)) => call_native(native, &mut args.into_iter().collect()),
+        other => Err(not_a_function(&other)),
+    }
+}
+
+fn call_native(native: NativeFunc, args: &mut MalList) -> Result<Mal> {
+    let value = native(args)?;
+    limits::check_value(&value)?;
+    Ok(value)
+}
+
+/// Calls a closure, following its tail calls.
+pub(crate) fn call_closure(mut closure: Rc<Closure>, mut args: Vec<Mal>) -> Result<Mal> {
+    let _call = limits::enter()?;
+    loop {
+        limits::step()?;
+        match run_closure(&closure, args)? {
+            Flow::Value(value) => return Ok(value),
+            Flow::TailCall(next, next_args) => {
+                closure = next;
+                args = next_args;
+            }
+            Flow::Recur(_) => unreachable!("recur is handled by run_closure"),
         }
     }
-    
-    // Bind the arguments in the closure env.
-    let mut is_vararg = false;
-    for name in arg_names.drain(..) {
-        let symbol = name.clone();
-        if symbol.text() == "&" {
-            is_vararg = true;
-            continue;
-        }
-        if ! is_vararg {
-            let value = args.pop_front().unwrap();
-            closure_env.set(symbol, value);
-        } else {
-            closure_env.set(symbol, args.clone());
-            args.clear();
+}
+
+/// Runs the body of a closure until it returns a value or makes a tail call.
+fn run_closure(closure: &Rc<Closure>, args: Vec<Mal>) -> Result<Flow> {
+    let arity = select_arity(closure, args.len())?;
+    let mut frame = Frame { slots: vec![Mal::Nil; closure.lambda.nslots], closure: Some(closure) };
+    destructure::bind_args(&arity.params, arity.rest.as_ref(), args, &mut frame)
+        .chain_err(|| format!("'{}': Invalid arguments", closure_name(closure)))?;
+    loop {
+        let flow = match arity.body {
+            Body::Tree(ref node) => eval_node(node, &mut frame)?,
+            Body::Code(ref chunk) => vm::run(chunk, &mut frame)?,
+        };
+        match flow {
+            Flow::Recur(values) => {
+                limits::step()?;
+                destructure::bind_args(&arity.params, arity.rest.as_ref(), values, &mut frame)
+                    .chain_err(|| "recur: Invalid arguments")?;
+            }
+            flow => return Ok(flow),
         }
     }
+}
 
-    eval(body, closure_env)?;
-    // Clone the reduced result for less garbage.
-    Ok(body.clone())
+fn closure_name(closure: &Closure) -> &str {
+    match closure.lambda.name {
+        Some(ref name) => name.text(),
+        None => "#<function>",
+    }
+}
+
+/// Describes the arities of a function for error messages, eg. "[x] [x y & more]".
+fn arities_str(closure: &Closure) -> String {
+    closure.arities().iter()
+        .map(|arity| printer::pr_str(&arity.params.clone().into(), true))
+        .collect::<Result<Vec<_>>>()
+        .map(|params| params.join(" "))
+        .unwrap_or_default()
+}
+
+/// Picks the arity for the arguments: the fixed one that takes exactly as
+/// many, or else the variadic one, wherever they are in the `fn*`.
+fn select_arity(closure: &Closure, nargs: usize) -> Result<&LambdaArity> {
+    let arities = &closure.lambda.arities;
+    let fixed = arities.iter().find(|arity| arity.rest.is_none() && arity.accepts(nargs));
+    if let Some(arity) = fixed.or_else(|| arities.iter().find(|arity| arity.accepts(nargs))) {
+        return Ok(arity);
+    }
+    let name = closure_name(closure);
+    if arities.len() > 1 {
+        bail!("'{}' has no arity for {} arguments, the arities are: {}",
+            name, nargs, arities_str(closure));
+    }
+    match arities[0].rest {
+        None => bail!("'{}' takes {} arguments, found {}",
+            name, arities[0].params.len(), nargs),
+        Some(_) => bail!("'{}' takes {} or more arguments, found {}!",
+            name, arities[0].params.len(), nargs),
+    }
 }
@@ -0,0 +1,300 @@
+//! Collection of reference cycles.
+//!
+//! Values are reference counted, so they are freed as soon as nothing uses
+//! them, unless they are part of a cycle. Atoms are the only values that
+//! change after they are made, so every cycle between values goes through
+//! an atom, like an atom holding a closure that captured the atom. Cycles
+//! through globals, like a recursive function, are kept by the environment
+//! anyway, and freed by `Env::clear_vars`.
+//!
+//! A collection sets the atoms that can't be reached any more to nil, which
+//! breaks their cycles. It marks what the globals of every environment
+//! reach first. What is left might still be held from elsewhere, eg. by the
+//! host, so the reference counts of the atoms, closures and lazy sequences
+//! left are compared with the references found between them. Those with
+//! more references than were found are kept, with everything they reach.
+//!
+//! A list, vector, map or set that is held more than once, or is big enough
+//! to share parts of itself with the collections made from it, may be held
+//! from elsewhere too, so everything in it is kept. This keeps some cycles,
+//! but never frees what the host holds.
+//!
+//! The locals of running functions aren't found this way, so collections
+//! only run between top-level evaluations: when `(gc)` asked for one, or
+//! once enough atoms were made since the last one.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+use types::{Mal, MalAtom, MalFunc};
+use env::Globals;
+
+/// The fewest atoms to make before a collection runs by itself.
+const MIN_THRESHOLD: usize = 1024;
+
+/// The most items a collection can have before it may share nodes with
+/// other collections, which its reference count doesn't show.
+const MAX_UNSHARED_LEN: usize = 64;
+
+/// Statistics of the collections on this thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// How many collections have run.
+    pub collections: u64,
+    /// How many atoms are alive.
+    pub atoms: usize,
+    /// How many atoms the last collection freed.
+    pub freed: usize,
+    /// How many atoms all collections freed.
+    pub total_freed: u64,
+}
+
+struct Heap {
+    atoms: Vec<Weak<RefCell<Mal>>>,
+    envs: Vec<Weak<Globals>>,
+    /// Atoms made since the last collection.
+    allocated: usize,
+    threshold: usize,
+    /// When to drop the atoms that were freed from `atoms`.
+    prune_at: usize,
+    requested: bool,
+    /// How many evaluations are running.
+    running: usize,
+    stats: GcStats,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        atoms: Vec::new(),
+        envs: Vec::new(),
+        allocated: 0,
+        threshold: MIN_THRESHOLD,
+        prune_at: MIN_THRESHOLD,
+        requested: false,
+        running: 0,
+        stats: GcStats::default(),
+    });
+}
+
+pub(crate) fn register_atom(atom: Weak<RefCell<Mal>>) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        // Atoms that were freed still take up their entry until then.
+        if heap.atoms.len() >= heap.prune_at {
+            heap.atoms.retain(|atom| atom.strong_count() > 0);
+            heap.prune_at = MIN_THRESHOLD.max(heap.atoms.len() * 2);
+        }
+        heap.atoms.push(atom);
+        heap.allocated += 1;
+    });
+}
+
+pub(crate) fn register_env(env: Weak<Globals>) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.envs.retain(|env| env.strong_count() > 0);
+        heap.envs.push(env);
+    });
+}
+
+/// Asks for a collection once the running evaluation is done.
+pub fn request() {
+    HEAP.with(|heap| heap.borrow_mut().requested = true);
+}
+
+/// Whether a collection is due, and can run now.
+pub(crate) fn should_collect() -> bool {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.running == 0 && (heap.requested || heap.allocated >= heap.threshold)
+    })
+}
+
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        GcStats {
+            atoms: heap.atoms.iter().filter(|atom| atom.strong_count() > 0).count(),
+            ..heap.stats
+        }
+    })
+}
+
+/// Keeps collections from running while it is alive.
+pub(crate) struct Running(());
+
+impl Running {
+    pub(crate) fn new() -> Running {
+        HEAP.with(|heap| heap.borrow_mut().running += 1);
+        Running(())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        HEAP.with(|heap| heap.borrow_mut().running -= 1);
+    }
+}
+
+/// Frees the atoms that can't be reached from the globals, the given roots
+/// or anything held from elsewhere. Must not run while evaluating.
+pub(crate) fn collect(roots: &[&Mal]) -> GcStats {
+    let (atoms, envs) = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        (heap.atoms.clone(), heap.envs.clone())
+    });
+
+    let mut live = HashSet::new();
+    let mut stack = Vec::new();
+    for root in roots {
+        walk((*root).clone(), |node, _| stack.push(node));
+    }
+    for env in envs.iter().filter_map(Weak::upgrade) {
+        env.for_each_value(|value| walk(value.clone(), |node, _| stack.push(node)));
+    }
+    mark(stack, &mut live);
+
+    // What the other atoms reach, with how many references to it were
+    // found there. Each is held once more by this map while counting.
+    let mut nodes: HashMap<usize, (Mal, usize)> = HashMap::new();
+    let mut held = Vec::new();
+    let mut pending = Vec::new();
+    for atom in atoms.iter().filter_map(MalAtom::upgrade) {
+        if ! live.contains(&atom.addr()) {
+            pending.push(atom.addr());
+            nodes.insert(atom.addr(), (atom.into(), 0));
+        }
+    }
+    while let Some(addr) = pending.pop() {
+        let node = nodes[&addr].0.clone();
+        let mut children = Vec::new();
+        for value in contents(&node) {
+            walk(value, |child, shared| {
+                if shared {
+                    held.push(child);
+                } else {
+                    children.push(child);
+                }
+            });
+        }
+        for child in children {
+            let addr = node_addr(&child).unwrap();
+            if live.contains(&addr) {
+                continue;
+            }
+            nodes.entry(addr).or_insert_with(|| {
+                pending.push(addr);
+                (child, 0)
+            }).1 += 1;
+        }
+    }
+    held.extend(nodes.values()
+        .filter(|(node, found)| ref_count(node) - 1 > *found)
+        .map(|(node, _)| node.clone()));
+    mark(held, &mut live);
+
+    let garbage: Vec<MalAtom> = nodes.into_iter()
+        .filter(|&(addr, _)| ! live.contains(&addr))
+        .filter_map(|(_, (node, _))| match node {
+            Mal::Atom(atom) => Some(atom),
+            _ => None,
+        })
+        .collect();
+    let freed = garbage.len();
+    for atom in &garbage {
+        atom.replace(Mal::Nil);
+    }
+    drop(garbage);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.atoms.retain(|atom| atom.strong_count() > 0);
+        heap.prune_at = MIN_THRESHOLD.max(heap.atoms.len() * 2);
+        heap.threshold = MIN_THRESHOLD.max(heap.atoms.len());
+        heap.allocated = 0;
+        heap.requested = false;
+        heap.stats.collections += 1;
+        heap.stats.freed = freed;
+        heap.stats.total_freed += freed as u64;
+    });
+    stats()
+}
+
+/// Marks the nodes, and everything they reach, as live.
+fn mark(mut stack: Vec<Mal>, live: &mut HashSet<usize>) {
+    while let Some(node) = stack.pop() {
+        if live.insert(node_addr(&node).unwrap()) {
+            for value in contents(&node) {
+                walk(value, |child, _| stack.push(child));
+            }
+        }
+    }
+}
+
+/// The address of an atom, closure or lazy sequence.
+fn node_addr(value: &Mal) -> Option<usize> {
+    match *value {
+        Mal::Atom(ref atom) => Some(atom.addr()),
+        Mal::Fn(MalFunc::Closure(ref closure)) => Some(Rc::as_ptr(closure) as usize),
+        Mal::Lazy(ref lazy) => Some(lazy.addr()),
+        _ => None,
+    }
+}
+
+fn ref_count(node: &Mal) -> usize {
+    match *node {
+        Mal::Atom(ref atom) => atom.ref_count(),
+        Mal::Fn(MalFunc::Closure(ref closure)) => Rc::strong_count(closure),
+        Mal::Lazy(ref lazy) => lazy.ref_count(),
+        _ => unreachable!("Not a node: {}", node.type_name()),
+    }
+}
+
+/// The values an atom, closure or lazy sequence holds. The thunks of lazy
+/// sequences can't be looked into; what they hold is found to be held from
+/// elsewhere.
+fn contents(node: &Mal) -> Vec<Mal> {
+    match *node {
+        Mal::Atom(ref atom) => vec![atom.get()],
+        Mal::Fn(MalFunc::Closure(ref closure)) => closure.captures.clone(),
+        Mal::Lazy(ref lazy) => match lazy.realized() {
+            Some((first, rest)) => vec![first, rest],
+            None => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Finds the atoms, closures and lazy sequences in a value, looking inside
+/// collections, and tells whether each was inside a shared collection.
+fn walk<F>(value: Mal, mut found: F) where F: FnMut(Mal, bool) {
+    let mut values = vec![(value, false)];
+    while let Some((value, shared)) = values.pop() {
+        let shared = shared || is_shared(&value);
+        match value {
+            Mal::List(ref list) => values.extend(list.iter().map(|item| (item.clone(), shared))),
+            Mal::Arr(ref arr) => values.extend(arr.iter().map(|item| (item.clone(), shared))),
+            Mal::Set(ref set) => values.extend(set.iter().map(|item| (item.clone(), shared))),
+            Mal::Map(ref map) => {
+                for (key, value) in map.iter() {
+                    values.push((key.clone(), shared));
+                    values.push((value.clone(), shared));
+                }
+            }
+            Mal::Atom(_) | Mal::Fn(MalFunc::Closure(_)) | Mal::Lazy(_) => found(value, shared),
+            _ => {}
+        }
+    }
+}
+
+/// Whether a collection may be held from elsewhere than where `walk` found
+/// it. The copy `walk` holds is one of its references.
+fn is_shared(value: &Mal) -> bool {
+    let (refs, len) = match *value {
+        Mal::List(ref list) => (list.ref_count(), list.len()),
+        Mal::Arr(ref arr) => (arr.ref_count(), arr.len()),
+        Mal::Map(ref map) => (map.ref_count(), map.len()),
+        Mal::Set(ref set) => (set.ref_count(), set.len()),
+        _ => return false,
+    };
+    refs > 2 || len > MAX_UNSHARED_LEN
+}
@@ -0,0 +1,199 @@
+//! An environment with the native functions, and the options it runs with.
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use types::Mal;
+use env::Env;
+use core::core_env;
+use eval;
+use printer::{self, PrintLength};
+use gc::{self, GcStats};
+use limits::{self, Limits, CancelToken};
+use sandbox::{self, Sandbox};
+use errors::*;
+
+/// How analyzed code is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Walk the analyzed nodes.
+    #[default]
+    Tree,
+    /// Compile to bytecode for the `vm`.
+    Vm,
+}
+
+impl FromStr for Engine {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Engine> {
+        match name {
+            "tree" => Ok(Engine::Tree),
+            "vm" => Ok(Engine::Vm),
+            _ => bail!("Unknown engine '{}', expected 'tree' or 'vm'", name),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Engine::Tree => write!(f, "tree"),
+            Engine::Vm => write!(f, "vm"),
+        }
+    }
+}
+
+/// Evaluates forms in an environment made by `core_env`.
+pub struct Interpreter {
+    env: Env,
+    limits: Limits,
+    cancel: CancelToken,
+    timeout: Option<Duration>,
+    sandbox: Rc<Sandbox>,
+    print_length: Option<usize>,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            env: core_env(),
+            limits: Limits::default(),
+            cancel: CancelToken::new(),
+            timeout: None,
+            sandbox: Rc::new(Sandbox::default()),
+            print_length: None,
+        }
+    }
+
+    /// Picks the engine from `MAL_ENGINE` if it is set.
+    pub fn from_env() -> Result<Interpreter> {
+        let interpreter = Interpreter::new();
+        if let Ok(name) = ::std::env::var("MAL_ENGINE") {
+            interpreter.set_engine(name.parse().chain_err(|| "Invalid MAL_ENGINE")?);
+        }
+        Ok(interpreter)
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.env.engine()
+    }
+
+    pub fn set_engine(&self, engine: Engine) {
+        self.env.set_engine(engine);
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Sets the limits that evaluation fails with `ErrorKind::LimitExceeded`
+    /// beyond. Steps are counted for each call of `eval`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// A token that stops the evaluations of this interpreter when it is
+    /// cancelled, from any thread.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Sets how long each call of `eval` may run before it fails with
+    /// `ErrorKind::Interrupted`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+
+    /// Sets what the native functions may access, which they fail with
+    /// `ErrorKind::SandboxDenied` beyond.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.sandbox = Rc::new(sandbox);
+    }
+
+    pub fn print_length(&self) -> Option<usize> {
+        self.print_length
+    }
+
+    /// Sets how many items of a lazy sequence are printed, by `print` and
+    /// the printing functions, before the rest is left out as `...`.
+    pub fn set_print_length(&mut self, print_length: Option<usize>) {
+        self.print_length = print_length;
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn env_mut(&mut self) -> &mut Env {
+        &mut self.env
+    }
+
+    /// Evaluates a form and returns its value. Afterwards, the cycles of
+    /// unreachable atoms are collected if a collection is due.
+    ///
+    /// If it is interrupted, the definitions made so far are kept, and the
+    /// scopes and namespaces it entered are left as usual.
+    pub fn eval(&mut self, form: &Mal) -> Result<Mal> {
+        let res = self.run(|env| eval::eval(form, env));
+        if gc::should_collect() {
+            let roots: Vec<&Mal> = res.as_ref().into_iter().collect();
+            gc::collect(&roots);
+        }
+        res
+    }
+
+    /// Prints a value like `pr-str` or `str`. Printing realizes lazy
+    /// sequences, so it is limited and can be interrupted like `eval`.
+    pub fn print(&mut self, value: &Mal, readably: bool) -> Result<String> {
+        self.run(|_| printer::pr_str(value, readably))
+    }
+
+    /// Runs code with the limits, token, timeout, sandbox and print length
+    /// of this interpreter.
+    fn run<T, F>(&mut self, func: F) -> Result<T> where F: FnOnce(&Env) -> Result<T> {
+        let _running = gc::Running::new();
+        let _sandbox = sandbox::Scope::enter(self.sandbox.clone());
+        let _print_length = PrintLength::set(self.print_length);
+        let budget = limits::Budget::start(self.limits, &self.cancel, self.timeout);
+        budget.finish(func(&self.env))
+    }
+
+    /// Collects the cycles of unreachable atoms now.
+    ///
+    /// Values held by the host are kept, with everything they reach: the
+    /// collector sees them from the reference counts of the atoms,
+    /// functions and collections in them.
+    pub fn gc(&mut self) -> GcStats {
+        gc::collect(&[])
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        gc::stats()
+    }
+}
+
+impl Drop for Interpreter {
+    /// Unbinds the globals, so the functions that refer to them can be
+    /// freed, and collects what that left unreachable.
+    fn drop(&mut self) {
+        self.env.clear_vars();
+        if gc::should_collect() {
+            gc::collect(&[]);
+        }
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
@@ -1,6 +1,10 @@
 #![recursion_limit = "1024"]
 #[macro_use]
 extern crate error_chain;
+extern crate im_rc;
+extern crate regex;
+#[cfg(feature = "repl")]
+extern crate rustyline;
 
 pub mod types;
 pub mod env;
@@ -9,6 +13,18 @@ pub mod macros;
 pub mod reader;
 pub mod printer;
 pub mod core;
+pub mod ast;
+mod analyzer;
+pub mod eval;
+pub mod vm;
+pub mod interpreter;
+pub mod modules;
+pub mod gc;
+pub mod limits;
+pub mod sandbox;
+#[cfg(feature = "repl")]
+pub mod repl;
+mod destructure;
 
 pub mod errors {
     fn linepos(src: &str, pos: usize) -> (usize, usize) {
@@ -16,7 +32,7 @@ pub mod errors {
         let mut lineno = 1;
         for (i, ch) in src.char_indices() {
             if i == pos {
-                let col = (&src[line_start..pos]).chars().count() + 1;
+                let col = src[line_start..pos].chars().count() + 1;
                 return (lineno, col);
             }
             if ch == '\n' {
@@ -24,8 +40,8 @@ pub mod errors {
                 lineno += 1;
             }
         }
-        let col = (&src[line_start..]).chars().count() + 1;
-        return (lineno, col);
+        let col = src[line_start..].chars().count() + 1;
+        (lineno, col)
     }
     
     fn linepos_str(src: &str, pos: usize) -> String {
@@ -37,21 +53,56 @@ pub mod errors {
     error_chain! {
         errors {
             Lexer { pos: usize, source: String, msg: String } {
-                display("Lexer: {}| {}", linepos_str(&source, *pos), msg)
+                display("Lexer: {}| {}", linepos_str(source, *pos), msg)
             }
             Reader { pos: usize, source: String, msg: String } {
-                display("Reader: {}| {}", linepos_str(&source, *pos), msg)
+                display("Reader: {}| {}", linepos_str(source, *pos), msg)
             }
             TypeError { expected: String, got: String } {
                 display("Type error: Expected {}, got {}", expected, got)
             }
+            LimitExceeded { limit: String, max: u64 } {
+                display("Exceeded the {} limit of {}", limit, max)
+            }
+            Interrupted(reason: String) {
+                display("Interrupted: {}", reason)
+            }
+            SandboxDenied(action: String) {
+                display("Not allowed by the sandbox: {}", action)
+            }
+        }
+    }
+
+    impl Clone for ErrorKind {
+        fn clone(&self) -> ErrorKind {
+            match *self {
+                ErrorKind::Msg(ref msg) => ErrorKind::Msg(msg.clone()),
+                ErrorKind::Lexer { pos, ref source, ref msg } => {
+                    ErrorKind::Lexer { pos, source: source.clone(), msg: msg.clone() }
+                }
+                ErrorKind::Reader { pos, ref source, ref msg } => {
+                    ErrorKind::Reader { pos, source: source.clone(), msg: msg.clone() }
+                }
+                ErrorKind::TypeError { ref expected, ref got } => {
+                    ErrorKind::TypeError { expected: expected.clone(), got: got.clone() }
+                }
+                ErrorKind::LimitExceeded { ref limit, max } => {
+                    ErrorKind::LimitExceeded { limit: limit.clone(), max }
+                }
+                ErrorKind::Interrupted(ref reason) => ErrorKind::Interrupted(reason.clone()),
+                ErrorKind::SandboxDenied(ref action) => ErrorKind::SandboxDenied(action.clone()),
+            }
         }
     }
 }
 
 pub use errors::*;
-pub use types::{Mal, MalList, MalArr, MalMap, Keyword, Symbol, MalFunc};
+pub use types::{Mal, MalList, MalArr, MalMap, MalSet, MalRegex, LazySeq, Keyword, Symbol, MalFunc, MalAtom, Closure, Arity};
 pub use env::Env;
 pub use reader::read_str;
 pub use printer::pr_str;
 pub use core::core_env;
+pub use interpreter::{Interpreter, Engine};
+pub use gc::GcStats;
+pub use limits::{Limits, CancelToken};
+pub use sandbox::Sandbox;
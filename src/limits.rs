@@ -0,0 +1,365 @@
+//! Limits on the resources an evaluation may use, and the ways to stop it
+//! early, so that code from an untrusted source can't hang or crash the
+//! host.
+//!
+//! The limits of the `Interpreter` apply while it evaluates a form. Each
+//! call and each `recur` is a step, and the calls that haven't returned are
+//! the depth. Collections are checked when they are returned by a native
+//! function, and while lazy sequences are realized.
+//!
+//! How much stack a call takes depends on the code and the build, so
+//! besides the depth, the stack used since `eval` was called is limited.
+//! Values can nest deeper than that, since they are made a level at a
+//! time; reading and printing them is limited by their nesting instead.
+//!
+//! Every so many steps, and items of lazy sequences, the evaluation checks
+//! whether it was cancelled or ran out of time.
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use types::Mal;
+use errors::*;
+
+/// The default stack size, which leaves room in the 8MB stack of a main
+/// thread for the host, and for the call that goes past it.
+pub const DEFAULT_MAX_STACK: usize = 6 * 1024 * 1024;
+
+/// The default nesting depth, which reading and printing stay well within
+/// the stack of a thread at.
+pub const DEFAULT_MAX_NESTING: usize = 1000;
+
+/// The limits of an evaluation; `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How many calls and `recur`s a top-level form may make.
+    pub max_steps: Option<u64>,
+    /// How deeply calls may nest.
+    pub max_depth: Option<usize>,
+    /// How many bytes of stack evaluation may use. Lower it when evaluating
+    /// on a thread with a smaller stack.
+    pub max_stack: Option<usize>,
+    /// How many items a collection, or bytes a string, may have.
+    pub max_collection_size: Option<usize>,
+    /// How deeply collections may nest when they are read or printed.
+    pub max_nesting: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_steps: None,
+            max_depth: None,
+            max_stack: Some(DEFAULT_MAX_STACK),
+            max_collection_size: None,
+            max_nesting: Some(DEFAULT_MAX_NESTING),
+        }
+    }
+}
+
+impl Limits {
+    /// No limits at all, not even on the stack.
+    pub fn none() -> Limits {
+        Limits {
+            max_steps: None,
+            max_depth: None,
+            max_stack: None,
+            max_collection_size: None,
+            max_nesting: None,
+        }
+    }
+}
+
+/// How often to check for interruptions, in steps or items.
+const POLL_INTERVAL: u32 = 256;
+
+/// Stops the evaluations of an interpreter, eg. from another thread.
+///
+/// Cancelling makes the running evaluation, or else the next one, fail
+/// with `ErrorKind::Interrupted`, which resets the token.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Resets the token, returning whether it was cancelled.
+    fn take(&self) -> bool {
+        self.cancelled.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Why an evaluation was stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Interrupt {
+    Cancelled,
+    TimedOut(Duration),
+}
+
+impl Interrupt {
+    fn error(self) -> Error {
+        let reason = match self {
+            Interrupt::Cancelled => "cancelled".to_string(),
+            Interrupt::TimedOut(timeout) => format!("timed out after {:?}", timeout),
+        };
+        ErrorKind::Interrupted(reason).into()
+    }
+}
+
+struct State {
+    limits: Cell<Limits>,
+    steps: Cell<u64>,
+    depth: Cell<usize>,
+    /// How deeply the collection being read or printed is nested.
+    nesting: Cell<usize>,
+    /// Where the stack was when the outermost budget started.
+    stack_base: Cell<Option<usize>>,
+    ticks: Cell<u32>,
+    token: RefCell<Option<CancelToken>>,
+    /// When to time out, and after how long.
+    deadline: Cell<Option<(Instant, Duration)>>,
+    /// Once set, every check fails, so that the evaluation stops.
+    interrupted: Cell<Option<Interrupt>>,
+}
+
+thread_local! {
+    static STATE: State = State {
+        limits: Cell::new(Limits::default()),
+        steps: Cell::new(0),
+        depth: Cell::new(0),
+        nesting: Cell::new(0),
+        stack_base: Cell::new(None),
+        ticks: Cell::new(0),
+        token: RefCell::new(None),
+        deadline: Cell::new(None),
+        interrupted: Cell::new(None),
+    };
+}
+
+fn exceeded<T>(limit: &str, max: u64) -> Result<T> {
+    Err(ErrorKind::LimitExceeded { limit: limit.into(), max }.into())
+}
+
+/// Applies limits, and watches the token and the timeout, until it is
+/// dropped, with a fresh count of steps. What was there before is restored
+/// then.
+pub(crate) struct Budget {
+    limits: Limits,
+    steps: u64,
+    depth: usize,
+    stack_base: Option<usize>,
+    token: Option<CancelToken>,
+    deadline: Option<(Instant, Duration)>,
+    interrupted: Option<Interrupt>,
+}
+
+impl Budget {
+    pub(crate) fn start(limits: Limits, token: &CancelToken, timeout: Option<Duration>) -> Budget {
+        let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+        STATE.with(|state| Budget {
+            limits: state.limits.replace(limits),
+            steps: state.steps.replace(0),
+            depth: state.depth.get(),
+            stack_base: state.stack_base.replace(state.stack_base.get().or(Some(stack_position()))),
+            token: state.token.replace(Some(token.clone())),
+            deadline: state.deadline.replace(deadline),
+            interrupted: state.interrupted.replace(None),
+        })
+    }
+
+    /// Makes sure that an evaluation that failed because it was
+    /// interrupted fails with `ErrorKind::Interrupted`, even if the error
+    /// was wrapped on its way out.
+    pub(crate) fn finish<T>(self, res: Result<T>) -> Result<T> {
+        match (res, STATE.with(|state| state.interrupted.get())) {
+            (Err(_), Some(interrupt)) => Err(interrupt.error()),
+            (res, _) => res,
+        }
+    }
+}
+
+impl Drop for Budget {
+    fn drop(&mut self) {
+        STATE.with(|state| {
+            state.limits.set(self.limits);
+            state.steps.set(self.steps);
+            state.depth.set(self.depth);
+            state.stack_base.set(self.stack_base);
+            *state.token.borrow_mut() = self.token.take();
+            state.deadline.set(self.deadline);
+            state.interrupted.set(self.interrupted);
+        });
+    }
+}
+
+/// Counts a step.
+#[inline]
+pub(crate) fn step() -> Result<()> {
+    STATE.with(|state| {
+        let steps = state.steps.get() + 1;
+        state.steps.set(steps);
+        match state.limits.get().max_steps {
+            Some(max) if steps > max => exceeded("step", max),
+            _ => tick_in(state),
+        }
+    })
+}
+
+/// Counts some work that isn't a step, like realizing an item of a lazy
+/// sequence, checking for interruptions every so often.
+#[inline]
+pub(crate) fn tick() -> Result<()> {
+    STATE.with(tick_in)
+}
+
+#[inline]
+fn tick_in(state: &State) -> Result<()> {
+    let ticks = state.ticks.get() + 1;
+    if ticks < POLL_INTERVAL {
+        state.ticks.set(ticks);
+        return Ok(());
+    }
+    state.ticks.set(0);
+    poll(state)
+}
+
+fn poll(state: &State) -> Result<()> {
+    if state.interrupted.get().is_none() {
+        let cancelled = state.token.borrow().as_ref().is_some_and(CancelToken::take);
+        let interrupt = match state.deadline.get() {
+            _ if cancelled => Some(Interrupt::Cancelled),
+            Some((deadline, timeout)) if Instant::now() >= deadline => Some(Interrupt::TimedOut(timeout)),
+            _ => None,
+        };
+        state.interrupted.set(interrupt);
+    }
+    match state.interrupted.get() {
+        Some(interrupt) => Err(interrupt.error()),
+        None => Ok(()),
+    }
+}
+
+/// Whether the running evaluation was interrupted.
+pub(crate) fn interrupted() -> bool {
+    STATE.with(|state| state.interrupted.get().is_some())
+}
+
+/// Whether the error, or one it was chained onto, is a limit that was
+/// exceeded.
+pub(crate) fn is_exceeded(mut err: &Error) -> bool {
+    loop {
+        if let ErrorKind::LimitExceeded { .. } = *err.kind() {
+            return true;
+        }
+        match err.1.next_error.as_ref().and_then(|next| next.downcast_ref::<Error>()) {
+            Some(next) => err = next,
+            None => return false,
+        }
+    }
+}
+
+/// A call that hasn't returned yet.
+pub(crate) struct Call(());
+
+/// Counts a call until the returned value is dropped.
+#[inline]
+pub(crate) fn enter() -> Result<Call> {
+    STATE.with(|state| {
+        let limits = state.limits.get();
+        let depth = state.depth.get() + 1;
+        if let Some(max) = limits.max_depth {
+            if depth > max {
+                return exceeded("call depth", max as u64);
+            }
+        }
+        if let (Some(max), Some(base)) = (limits.max_stack, state.stack_base.get()) {
+            if base.abs_diff(stack_position()) > max {
+                return exceeded("stack size", max as u64);
+            }
+        }
+        state.depth.set(depth);
+        Ok(Call(()))
+    })
+}
+
+impl Drop for Call {
+    #[inline]
+    fn drop(&mut self) {
+        STATE.with(|state| state.depth.set(state.depth.get() - 1));
+    }
+}
+
+/// A collection being read or printed, inside the ones around it.
+pub(crate) struct Nested(());
+
+/// Counts a level of nesting until the returned value is dropped. During
+/// an evaluation the stack is checked too, like for calls.
+#[inline]
+pub(crate) fn nest() -> Result<Nested> {
+    STATE.with(|state| {
+        let limits = state.limits.get();
+        let nesting = state.nesting.get() + 1;
+        if let Some(max) = limits.max_nesting {
+            if nesting > max {
+                return exceeded("nesting depth", max as u64);
+            }
+        }
+        if let (Some(max), Some(base)) = (limits.max_stack, state.stack_base.get()) {
+            if base.abs_diff(stack_position()) > max {
+                return exceeded("stack size", max as u64);
+            }
+        }
+        state.nesting.set(nesting);
+        Ok(Nested(()))
+    })
+}
+
+impl Drop for Nested {
+    #[inline]
+    fn drop(&mut self) {
+        STATE.with(|state| state.nesting.set(state.nesting.get() - 1));
+    }
+}
+
+/// The address of a local, which tells how far the stack has grown.
+#[inline(never)]
+fn stack_position() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+/// Checks the number of items of a collection being made.
+#[inline]
+pub(crate) fn check_size(size: usize) -> Result<()> {
+    STATE.with(|state| {
+        match state.limits.get().max_collection_size {
+            Some(max) if size > max => exceeded("collection size", max as u64),
+            _ => Ok(()),
+        }
+    })
+}
+
+/// Checks the size of a value returned by a native function.
+pub(crate) fn check_value(value: &Mal) -> Result<()> {
+    match *value {
+        Mal::List(ref list) => check_size(list.len()),
+        Mal::Arr(ref arr) => check_size(arr.len()),
+        Mal::Map(ref map) => check_size(map.len()),
+        Mal::Set(ref set) => check_size(set.len()),
+        Mal::Str(ref string) => check_size(string.len()),
+        Mal::Bytes(ref bytes) => check_size(bytes.len()),
+        _ => Ok(()),
+    }
+}
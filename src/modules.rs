@@ -0,0 +1,167 @@
+//! Loading namespaces from `.mal` files with `require`, and plain files
+//! with `load-file`.
+//!
+//! The namespace `foo.bar-baz` is looked up as `foo/bar_baz.mal` in each
+//! directory of the search path. A namespace is only loaded once; its
+//! globals are shared by every namespace that requires it.
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env as std_env;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use types::Symbol;
+use env::Env;
+use eval::eval;
+use reader;
+use sandbox;
+use errors::*;
+
+/// The search path and the namespaces loaded so far. Clones share them.
+#[derive(Clone)]
+pub struct Modules {
+    inner: Rc<RefCell<ModuleState>>,
+}
+
+struct ModuleState {
+    search_path: Vec<PathBuf>,
+    loaded: HashSet<Symbol>,
+    /// Namespaces being loaded, to catch circular requires.
+    loading: Vec<Symbol>,
+}
+
+impl Modules {
+    /// Starts with the directories in `MAL_PATH`, or the current directory.
+    pub fn new() -> Modules {
+        let search_path = match std_env::var_os("MAL_PATH") {
+            Some(paths) => std_env::split_paths(&paths).collect(),
+            None => vec![PathBuf::from(".")],
+        };
+        Modules {
+            inner: Rc::new(RefCell::new(ModuleState {
+                search_path,
+                loaded: HashSet::new(),
+                loading: Vec::new(),
+            }))
+        }
+    }
+
+    pub fn search_path(&self) -> Vec<PathBuf> {
+        self.inner.borrow().search_path.clone()
+    }
+
+    pub fn set_search_path(&self, search_path: Vec<PathBuf>) {
+        self.inner.borrow_mut().search_path = search_path;
+    }
+
+    pub fn is_loaded(&self, ns: &Symbol) -> bool {
+        self.inner.borrow().loaded.contains(ns)
+    }
+
+    fn find(&self, ns: &Symbol) -> Result<PathBuf> {
+        let mut relative = PathBuf::new();
+        for part in ns.text().split('.') {
+            // Each part must be a plain file name, so that the path stays
+            // inside the directories of the search path.
+            let part = part.replace('-', "_");
+            let mut components = Path::new(&part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) if ! part.contains(['/', '\\']) => {}
+                _ => bail!("'{}' is not a valid namespace name", ns.text()),
+            }
+            relative.push(part);
+        }
+        relative.set_extension("mal");
+        for dir in &self.inner.borrow().search_path {
+            let path = dir.join(&relative);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+        bail!("Could not find '{}' on the search path", relative.display());
+    }
+}
+
+impl Default for Modules {
+    fn default() -> Modules {
+        Modules::new()
+    }
+}
+
+impl fmt::Debug for Modules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Modules {{ {:?} }}", self.inner.borrow().search_path)
+    }
+}
+
+impl PartialEq for Modules {
+    fn eq(&self, other: &Modules) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for Modules {}
+
+/// Loads the namespace, unless it is already loaded.
+pub fn require(ns: &Symbol, env: &Env) -> Result<()> {
+    let modules = env.modules().clone();
+    if modules.is_loaded(ns) {
+        return Ok(());
+    }
+    load(ns, env, &modules)
+        .chain_err(|| format!("require: Could not load namespace '{}'", ns.text()))?;
+    modules.inner.borrow_mut().loaded.insert(ns.clone());
+    Ok(())
+}
+
+fn load(ns: &Symbol, env: &Env, modules: &Modules) -> Result<()> {
+    if modules.inner.borrow().loading.contains(ns) {
+        bail!("Circular require of namespace '{}'", ns.text());
+    }
+    let path = modules.find(ns)?;
+    sandbox::check_read(&path)?;
+    let text = fs::read_to_string(&path)
+        .chain_err(|| format!("Could not read '{}'", path.display()))?;
+
+    // The file is evaluated in its own namespace, and then the requiring
+    // one is restored.
+    let module_env = env.fork();
+    let outer_ns = module_env.ns();
+    module_env.set_ns(ns.clone());
+    modules.inner.borrow_mut().loading.push(ns.clone());
+    let res = reader::read_all(&text).and_then(|forms| {
+        for form in forms {
+            eval(&form, &module_env)?;
+        }
+        Ok(())
+    });
+    modules.inner.borrow_mut().loading.retain(|loading| loading != ns);
+    let module_ns = module_env.ns();
+    module_env.set_ns(outer_ns);
+    res.chain_err(|| format!("In '{}'", path.display()))?;
+
+    if module_ns != *ns {
+        bail!("'{}' is in namespace '{}' instead of '{}'",
+            path.display(), module_ns.text(), ns.text());
+    }
+    Ok(())
+}
+
+/// Evaluates the forms of a file in the current namespace, like `load-file`.
+/// Unlike `require`, the file is read again every time. A namespace the
+/// file switches to stays with the file, even if it fails part-way.
+pub fn load_file(path: &str, env: &Env) -> Result<()> {
+    sandbox::check_read(path)?;
+    let text = fs::read_to_string(path)
+        .chain_err(|| format!("'load-file': could not read '{}'", path))?;
+    let outer_ns = env.ns();
+    let res = reader::read_all(&text).and_then(|forms| {
+        for form in forms {
+            eval(&form, env)?;
+        }
+        Ok(())
+    });
+    env.set_ns(outer_ns);
+    res.chain_err(|| format!("In '{}'", path))
+}
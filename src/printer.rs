@@ -1,6 +1,31 @@
-use types::{Mal, MapKey, MalFunc};
+use types::{Mal, MalFunc, Arity};
+use limits;
+use errors::*;
+use std::cell::Cell;
 use std::fmt::Write;
 
+thread_local! {
+    static PRINT_LENGTH: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Limits how many items of a lazy sequence are printed until it is
+/// dropped, and then restores the limit from before.
+pub(crate) struct PrintLength {
+    previous: Option<usize>,
+}
+
+impl PrintLength {
+    pub(crate) fn set(length: Option<usize>) -> PrintLength {
+        PrintLength { previous: PRINT_LENGTH.with(|current| current.replace(length)) }
+    }
+}
+
+impl Drop for PrintLength {
+    fn drop(&mut self) {
+        PRINT_LENGTH.with(|current| current.set(self.previous));
+    }
+}
+
 fn pr_malstr_into(s: &str, string: &mut String, print_readably: bool) {
     if ! print_readably {
         string.push_str(s);
@@ -20,6 +45,21 @@ fn pr_malstr_into(s: &str, string: &mut String, print_readably: bool) {
                     string.push('\\');
                     string.push('n');
                 }
+                '\t' => {
+                    string.push('\\');
+                    string.push('t');
+                }
+                '\r' => {
+                    string.push('\\');
+                    string.push('r');
+                }
+                '\0' => {
+                    string.push('\\');
+                    string.push('0');
+                }
+                ch if ch.is_control() => {
+                    write!(string, "\\u{:04x}", ch as u32).unwrap();
+                }
                 ch => string.push(ch),
             }
         }
@@ -27,8 +67,33 @@ fn pr_malstr_into(s: &str, string: &mut String, print_readably: bool) {
     }
 }
 
-pub fn pr_str_into(mal: &Mal, string: &mut String, print_readably: bool) {
+fn pr_malchar_into(ch: char, string: &mut String, print_readably: bool) {
+    if ! print_readably {
+        string.push(ch);
+        return;
+    }
+    string.push('\\');
+    match ch {
+        '\n' => string.push_str("newline"),
+        ' ' => string.push_str("space"),
+        '\t' => string.push_str("tab"),
+        '\r' => string.push_str("return"),
+        '\u{8}' => string.push_str("backspace"),
+        '\u{c}' => string.push_str("formfeed"),
+        ch if ch.is_control() => write!(string, "u{:04x}", ch as u32).unwrap(),
+        ch => string.push(ch),
+    }
+}
+
+/// Prints the value into the string. This can fail, since printing a lazy
+/// sequence realizes it.
+pub fn pr_str_into(mal: &Mal, string: &mut String, print_readably: bool) -> Result<()> {
     use types::Mal::*;
+    // Collections are printed by recursion, so how deep they nest is limited.
+    let _nested = match *mal {
+        List(_) | Arr(_) | Map(_) | Set(_) | Atom(_) | Lazy(_) => Some(limits::nest()?),
+        _ => None,
+    };
     match *mal {
         Num(num) => write!(string, "{}", num).unwrap(),
         Sym(ref sym) => string.push_str(sym.text()),
@@ -42,27 +107,46 @@ pub fn pr_str_into(mal: &Mal, string: &mut String, print_readably: bool) {
         Str(ref s) => {
             pr_malstr_into(s, string, print_readably);
         }
+        Char(ch) => {
+            pr_malchar_into(ch, string, print_readably);
+        }
+        Bytes(ref bytes) => {
+            string.push_str("#bytes \"");
+            for byte in bytes.iter() {
+                write!(string, "{:02x}", byte).unwrap();
+            }
+            string.push('"');
+        }
+        Regex(ref regex) if ! print_readably => string.push_str(regex.pattern()),
+        Regex(ref regex) => {
+            // The pattern is kept as written, only quotes need escaping.
+            string.push_str("#\"");
+            for ch in regex.pattern().chars() {
+                if ch == '"' {
+                    string.push('\\');
+                }
+                string.push(ch);
+            }
+            string.push('"');
+        }
         Fn(ref f) => {
             match *f {
                 MalFunc::Native(name, _) => string.push_str(name),
-                MalFunc::Closure(ref args, ref _env, ref body) |
-                MalFunc::NamedClosure(_, ref args, ref _env, ref body) => {
+                MalFunc::Closure(ref closure) => {
+                    let arities = closure.arities();
                     if ! print_readably {
                         string.push_str("#<function>");
+                    } else if arities.len() == 1 {
+                        string.push_str("(fn* ");
+                        pr_arity_into(&arities[0], string, print_readably)?;
+                        string.push(')');
                     } else {
-                        string.push_str("(fn* (");
-                        let len = args.len();
-                        if len != 0 {
-                            let last = len - 1;
-                            for (i, sym) in args.iter().enumerate() {
-                                string.push_str(sym.text());
-                                if i != last {
-                                    string.push(' ');
-                                }
-                            }
+                        string.push_str("(fn*");
+                        for arity in arities {
+                            string.push_str(" (");
+                            pr_arity_into(arity, string, print_readably)?;
+                            string.push(')');
                         }
-                        string.push_str(") ");
-                        pr_str_into(body, string, print_readably);
                         string.push(')');
                     }
                 }
@@ -74,7 +158,7 @@ pub fn pr_str_into(mal: &Mal, string: &mut String, print_readably: bool) {
             if len != 0 {
                 let last = len - 1;
                 for (i, item) in list.iter().enumerate() {
-                    pr_str_into(item, string, print_readably);
+                    pr_str_into(item, string, print_readably)?;
                     if i != last {
                         string.push(' ');
                     }
@@ -88,7 +172,7 @@ pub fn pr_str_into(mal: &Mal, string: &mut String, print_readably: bool) {
             if len != 0 {
                 let last = len - 1;
                 for (i, item) in arr.iter().enumerate() {
-                    pr_str_into(item, string, print_readably);
+                    pr_str_into(item, string, print_readably)?;
                     if i != last {
                         string.push(' ');
                     }
@@ -98,26 +182,77 @@ pub fn pr_str_into(mal: &Mal, string: &mut String, print_readably: bool) {
         }
         Map(ref map) => {
             string.push('{');
-            for (k, v) in map.inner.iter() {
-                match *k {
-                    MapKey::Str(ref s) => pr_malstr_into(s, string, print_readably),
-                    MapKey::Kw(ref kw) => {
-                        string.push(':');
-                        string.push_str(kw.symbol());
-                    }
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i != 0 {
+                    string.push(' ');
                 }
+                pr_str_into(k, string, print_readably)?;
                 string.push(' ');
-                pr_str_into(v, string, print_readably);
+                pr_str_into(v, string, print_readably)?;
+            }
+            string.push('}');
+        }
+        Set(ref set) => {
+            string.push_str("#{");
+            for (i, item) in set.iter().enumerate() {
+                if i != 0 {
+                    string.push(' ');
+                }
+                pr_str_into(item, string, print_readably)?;
             }
             string.push('}');
         }
+        Atom(ref atom) => {
+            string.push_str("(atom ");
+            pr_str_into(&atom.get(), string, print_readably)?;
+            string.push(')');
+        }
+        Lazy(_) => {
+            // Only realize as much of the sequence as is printed, which
+            // may be cut short.
+            string.push('(');
+            let max = PRINT_LENGTH.with(Cell::get);
+            let mut seq = mal.clone();
+            let mut printed = 0;
+            while let Some((item, rest)) = seq.first_rest()? {
+                if printed != 0 {
+                    string.push(' ');
+                }
+                if max == Some(printed) {
+                    string.push_str("...");
+                    break;
+                }
+                pr_str_into(&item, string, print_readably)?;
+                printed += 1;
+                seq = rest;
+                limits::tick()?;
+            }
+            string.push(')');
+        }
     }
+    Ok(())
 }
 
-pub fn pr_str(mal: &Mal, print_readably: bool) -> String {
+pub fn pr_str(mal: &Mal, print_readably: bool) -> Result<String> {
     let mut string = String::new();
-    pr_str_into(mal, &mut string, print_readably);
+    pr_str_into(mal, &mut string, print_readably)?;
     //println!("pr_str({:?}) -> {:?}", mal, string);
-    string
+    Ok(string)
 }
 
+/// Writes the parameter list and body of a closure.
+fn pr_arity_into(arity: &Arity, string: &mut String, print_readably: bool) -> Result<()> {
+    string.push('(');
+    let len = arity.params.len();
+    if len != 0 {
+        let last = len - 1;
+        for (i, param) in arity.params.iter().enumerate() {
+            pr_str_into(param, string, print_readably)?;
+            if i != last {
+                string.push(' ');
+            }
+        }
+    }
+    string.push_str(") ");
+    pr_str_into(&arity.body, string, print_readably)
+}
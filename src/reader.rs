@@ -1,7 +1,8 @@
 use errors::*;
 use std::iter::Peekable;
 use std::str::CharIndices;
-use types::{MalList, Mal, Keyword, MalArr, MalMap, MapKey, Symbol};
+use types::{MalList, Mal, Keyword, MalArr, MalMap, MalSet, MalRegex, Symbol};
+use limits;
 
 // Tokens
 /* 
@@ -28,6 +29,7 @@ pub enum TokenKind {
     BrackClose, // ]
     CurlOpen, // {
     CurlClose, // }
+    HashCurlOpen, // #{
     ParOpen, // (
     ParClose, // )
     Apo, // '
@@ -36,6 +38,9 @@ pub enum TokenKind {
     Hat, // ^
     At, // @
     Str(String), // "with \" escapes"
+    Char(char), // \a, \newline, \u00e9
+    Regex(String), // #"regex \" pattern"
+    Tag(String), // #tag, for tagged literals like '#bytes "ff00"'
     SemiCTrail(String), // ;.*
     Ident(String), // A sequence of non-ws and non-specials
 }
@@ -57,7 +62,7 @@ pub struct Lexer<'a> {
 impl<'a> Lexer<'a> {
     pub fn new(text: &'a str) -> Lexer<'a> {
         Lexer {
-            text: text,
+            text,
             pos: 0,
             chars: text.char_indices().peekable(),
             next_token: None,
@@ -121,7 +126,7 @@ impl<'a> Lexer<'a> {
     
     fn eat_whitespace(&mut self) {
         loop {
-            let is_ws = self.chars.peek().map_or(false, |&(_, c)| c == ',' || c.is_whitespace());
+            let is_ws = self.chars.peek().is_some_and(|&(_, c)| c == ',' || c.is_whitespace());
             if is_ws {
                 self.advance().unwrap();
             } else {
@@ -147,6 +152,13 @@ impl<'a> Lexer<'a> {
                     '"' => string.push('"'),
                     '\\' => string.push('\\'),
                     'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    '0' => string.push('\0'),
+                    'u' => {
+                        let ch = self.read_unicode_escape()?;
+                        string.push(ch);
+                    }
                     ch => {
                         return self.err(format!("Invalid escape character: {:?}", ch));
                     }
@@ -157,6 +169,107 @@ impl<'a> Lexer<'a> {
         self.err(format!("Unterminated string: '\"{}'", string))
     }
     
+    /// Reads the code point of a '\\uXXXX' or '\\u{X...}' escape, after the 'u'.
+    fn read_unicode_escape(&mut self) -> Result<char> {
+        let mut digits = String::new();
+        if self.peek_is('{') {
+            self.chars.next();
+            loop {
+                match self.chars.next() {
+                    Some((_, '}')) => break,
+                    Some((_, ch)) if digits.len() < 6 => digits.push(ch),
+                    _ => return self.err("Unterminated unicode escape, expected '}'"),
+                }
+            }
+        } else {
+            for _ in 0..4 {
+                match self.chars.next() {
+                    Some((_, ch)) => digits.push(ch),
+                    None => return self.err("Unicode escape needs 4 hex digits"),
+                }
+            }
+        }
+        let code = parse_hex(&digits).and_then(::std::char::from_u32);
+        match code {
+            Some(ch) => Ok(ch),
+            _ => self.err(format!("Invalid unicode escape: '\\u{}'", digits)),
+        }
+    }
+    
+    /// Reads a regex pattern. Escapes are left for the regex engine, except
+    /// for escaped quotes.
+    fn read_regex(&mut self) -> Result<Token> {
+        let mut pattern = String::new();
+        while let Some((_, ch)) = self.chars.next() {
+            match ch {
+                '"' => return self.send_token(TokenKind::Regex(pattern)),
+                '\\' => {
+                    match self.chars.next() {
+                        Some((_, '"')) => pattern.push('"'),
+                        Some((_, next)) => {
+                            pattern.push('\\');
+                            pattern.push(next);
+                        }
+                        None => break,
+                    }
+                }
+                ch => pattern.push(ch),
+            }
+        }
+        self.err(format!("Unterminated regex: '#\"{}'", pattern))
+    }
+    
+    fn read_char(&mut self) -> Result<Token> {
+        let mut name = String::new();
+        // The first character is taken as-is, so that eg. '\(' works.
+        match self.chars.next() {
+            Some((_, ch)) if ! ch.is_whitespace() => name.push(ch),
+            _ => return self.err("Expected a character after '\\'"),
+        }
+        while let Some(&(_, next)) = self.chars.peek() {
+            if is_special_char(next) {
+                break;
+            }
+            name.push(next);
+            self.chars.next();
+        }
+        let mut chars = name.chars();
+        let first = chars.next().unwrap();
+        if chars.next().is_none() {
+            return self.send_token(TokenKind::Char(first));
+        }
+        let ch = match name.as_str() {
+            "newline" => '\n',
+            "space" => ' ',
+            "tab" => '\t',
+            "return" => '\r',
+            "backspace" => '\u{8}',
+            "formfeed" => '\u{c}',
+            _ if first == 'u' && name.len() == 5 => {
+                let code = parse_hex(&name[1..]).and_then(::std::char::from_u32);
+                match code {
+                    Some(ch) => ch,
+                    None => return self.err(format!("Invalid unicode character: '\\{}'", name)),
+                }
+            }
+            _ => return self.err(format!("Unknown character name: '\\{}'", name)),
+        };
+        self.send_token(TokenKind::Char(ch))
+    }
+    
+    /// Reads the rest of an identifier that starts with the given text.
+    fn read_ident(&mut self, mut ident: String) -> String {
+        while let Some(&(_, next)) = self.chars.peek() {
+            if ! is_special_char(next) {
+                ident.push(next);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        ident
+    }
+    
     #[inline]
     fn peek_is(&mut self, ch: char) -> bool {
         if let Some(&(_, peek)) = self.chars.peek() {
@@ -177,7 +290,6 @@ impl<'a> Lexer<'a> {
                     return self.err("Carriage return without newline!");
                 }
             } else if ch == '\n' {
-                self.chars.next();
                 return Ok(trail);
             } else {
                 trail.push(ch);
@@ -186,6 +298,18 @@ impl<'a> Lexer<'a> {
         Ok(trail)
     }
     
+    /// Skips whitespace and comments between forms.
+    fn eat_blank(&mut self) -> Result<()> {
+        loop {
+            self.eat_whitespace();
+            if self.next_token.is_none() && self.peek_is(';') {
+                self.trail()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+    
     #[inline]
     pub fn has_next(&mut self) -> bool {
         self.next_token.is_some() || self.chars.peek().is_some()
@@ -202,9 +326,10 @@ impl<'a> Lexer<'a> {
     
     #[inline]
     fn send_token(&mut self, kind: TokenKind) -> Result<Token> {
-        Ok(Token { kind: kind, start: self.pos, end: self.end() })
+        Ok(Token { kind, start: self.pos, end: self.end() })
     }
     
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Token> {
         use self::TokenKind::*;
         if self.next_token.is_some() {
@@ -223,6 +348,21 @@ impl<'a> Lexer<'a> {
             '[' => self.send_token(BrackOpen),
             ']' => self.send_token(BrackClose),
             '{' => self.send_token(CurlOpen),
+            '#' if self.peek_is('{') => {
+                self.chars.next();
+                self.send_token(HashCurlOpen)
+            }
+            '#' if self.peek_is('"') => {
+                self.chars.next();
+                self.read_regex()
+            }
+            '#' => {
+                let tag = self.read_ident(String::new());
+                if tag.is_empty() {
+                    return self.err("Expected a tag, set or regex after '#'");
+                }
+                self.send_token(Tag(tag))
+            }
             '}' => self.send_token(CurlClose),
             '\'' => self.send_token(Apo),
             '^' => self.send_token(Hat),
@@ -233,17 +373,11 @@ impl<'a> Lexer<'a> {
                 self.send_token(SemiCTrail(trail))
             }
             '"' => self.read_string(),
+            '\\' => self.read_char(),
             ch => {
                 let mut ident = String::new();
                 ident.push(ch);
-                while let Some(&(_, next)) = self.chars.peek() {
-                    if ! is_special_char(next) {
-                        ident.push(next);
-                        self.chars.next();
-                    } else {
-                        break;
-                    }
-                }
+                let ident = self.read_ident(ident);
                 self.send_token(Ident(ident))
             },
         }
@@ -255,44 +389,56 @@ pub fn read_str(text: &str) -> Result<Mal> {
     read_form(&mut lexer)
 }
 
-fn read_arr(lexer: &mut Lexer, _start: usize) -> Result<MalArr> {
-    let mut list = MalArr::new();
+/// Reads every form in the text, eg. the contents of a file.
+pub fn read_all(text: &str) -> Result<Vec<Mal>> {
+    let mut lexer = Lexer::new(text);
+    let mut forms = Vec::new();
     loop {
-        if lexer.peek().ok_or_else(|| Error::from("Unclosed list"))?.kind == TokenKind::BrackClose {
-            lexer.next().unwrap();
-            return Ok(list);
-        } else {
-            list.push_back(read_form(lexer)?);
+        lexer.eat_blank()?;
+        if ! lexer.has_next() {
+            return Ok(forms);
         }
+        forms.push(read_form(&mut lexer)?);
     }
 }
 
+fn read_arr(lexer: &mut Lexer, _start: usize) -> Result<MalArr> {
+    Ok(read_items(lexer, TokenKind::BrackClose)?.into_iter().collect())
+}
+
 fn read_list(lexer: &mut Lexer, _start: usize) -> Result<MalList> {
-    let mut list = MalList::new();
+    Ok(read_items(lexer, TokenKind::ParClose)?.into_iter().collect())
+}
+
+/// Reads forms until the closing token. They are gathered in a `Vec`,
+/// since pushing to a `Vector` takes a lot of stack at every level of
+/// nesting in a debug build.
+fn read_items(lexer: &mut Lexer, close: TokenKind) -> Result<Vec<Mal>> {
+    let mut items = Vec::new();
     loop {
-        if lexer.peek().ok_or_else(|| Error::from("Unclosed list"))?.kind == TokenKind::ParClose {
+        if lexer.peek().ok_or_else(|| Error::from("Unclosed list"))?.kind == close {
             lexer.next().unwrap();
-            return Ok(list);
+            return Ok(items);
         } else {
-            list.push_back(read_form(lexer)?);
+            items.push(read_form(lexer)?);
         }
     }
 }
 
 pub fn read_atom(mut ident: String) -> Result<Mal> {
-    let first = ident.chars().nth(0).unwrap();
+    let first = ident.chars().next().unwrap();
     Ok(match first {
         '-' | '+' => {
             if let Some(ch) = ident.chars().nth(1) {
                 match ch {
-                    '0' ... '9' => Mal::Num(ident.parse().chain_err(|| "Could not parse number")?),
+                    '0' ..= '9' => Mal::Num(ident.parse().chain_err(|| "Could not parse number")?),
                     _ => Mal::Sym(Symbol::new(ident)),
                 }
             } else {
                 Mal::Sym(Symbol::new(ident))
             }
         }
-        '0' ... '9' => Mal::Num(ident.parse().chain_err(|| "Could not parse number")?),
+        '0' ..= '9' => Mal::Num(ident.parse().chain_err(|| "Could not parse number")?),
         ':' => {
             ident.remove(0);
             Mal::Kw(Keyword::new(ident))
@@ -315,63 +461,129 @@ pub fn read_hash_map(lexer: &mut Lexer) -> Result<Mal> {
             lexer.next().unwrap();
             return Ok(map.into());
         } else {
-            let key: MapKey = match read_form(lexer)? {
-                Mal::Str(string) => string.into(),
-                Mal::Kw(kw) => kw.into(),
-                other_token => {
-                    bail!("Invalid key type for hashmap: {:?}", other_token);
-                }
-            };
+            let key = read_form(lexer)?;
             let value = read_form(lexer)?;
             map.insert(key, value);
         }
     }
 }
 
+pub fn read_set(lexer: &mut Lexer) -> Result<Mal> {
+    let mut set = MalSet::new();
+    loop {
+        if lexer.peek().ok_or_else(|| Error::from("unclosed set"))?.kind == TokenKind::CurlClose {
+            lexer.next().unwrap();
+            return Ok(set.into());
+        } else {
+            let item = read_form(lexer)?;
+            set.insert(item);
+        }
+    }
+}
+
+/// Parses hex digits, unlike `from_str_radix` without a sign.
+fn parse_hex(digits: &str) -> Option<u32> {
+    if digits.is_empty() || ! digits.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Parses the hex digits of a '#bytes' literal. Whitespace is ignored.
+fn parse_hex_bytes(hex: &str) -> ::std::result::Result<Vec<u8>, String> {
+    let digits = hex.chars().filter(|ch| ! ch.is_whitespace()).collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return Err(format!("Odd number of hex digits in bytes literal: {:?}", hex));
+    }
+    digits.chunks(2).map(|pair| {
+        let byte = pair.iter().collect::<String>();
+        parse_hex(&byte).map(|byte| byte as u8)
+            .ok_or_else(|| format!("Invalid hex byte in bytes literal: {:?}", byte))
+    }).collect()
+}
+
+fn read_tagged(lexer: &mut Lexer, start: usize, tag: &str) -> Result<Mal> {
+    let res = match tag {
+        "bytes" => {
+            match read_form(lexer)? {
+                Mal::Str(ref hex) => parse_hex_bytes(hex).map(Mal::from),
+                other => Err(format!("'#bytes' takes a string of hex digits, found {}", 
+                    other.type_name())),
+            }
+        }
+        _ => Err(format!("Unknown tag: #{}", tag)),
+    };
+    res.map_err(|msg| ErrorKind::Reader {
+        pos: start,
+        source: String::from(lexer.text),
+        msg,
+    }.into())
+}
+
+/// Reads the next form, in a list after the symbol: `'a` is `(quote a)`.
+fn read_wrapped(lexer: &mut Lexer, sym: &str) -> Result<Mal> {
+    let form = read_form(lexer)?;
+    Ok(list_with_sym![sym, form].into())
+}
+
+/// Reads `^meta target` as `(with-meta target meta)`.
+fn read_with_meta(lexer: &mut Lexer) -> Result<Mal> {
+    let meta = read_form(lexer)?;
+    let target = read_form(lexer)?;
+    Ok(list_with_sym!["with-meta", target, meta].into())
+}
+
 pub fn read_form(lexer: &mut Lexer) -> Result<Mal> {
     use self::TokenKind::*;
-    let token = lexer.next()?;
+    let mut token = lexer.next()?;
+    // Comments are skipped, like whitespace.
+    while let SemiCTrail(_) = token.kind {
+        token = lexer.next()?;
+    }
+    // Forms are read by recursion, so how deep they nest is limited. The
+    // forms that don't nest are read by `read_token`, which keeps the
+    // stack frame of this function small.
+    let _nested = limits::nest()?;
+    match token.kind {
+        ParOpen => Ok(read_list(lexer, token.start)?.into()),
+        BrackOpen => Ok(read_arr(lexer, token.start)?.into()),
+        CurlOpen => read_hash_map(lexer),
+        HashCurlOpen => read_set(lexer),
+        Apo => read_wrapped(lexer, "quote"),
+        BackTick => read_wrapped(lexer, "quasiquote"),
+        Tilde => read_wrapped(lexer, "unquote"),
+        Tadpole => read_wrapped(lexer, "splice-unquote"),
+        At => read_wrapped(lexer, "deref"),
+        Tag(tag) => read_tagged(lexer, token.start, &tag),
+        Hat => read_with_meta(lexer),
+        _ => read_token(lexer, token),
+    }
+}
+
+/// Reads a form that is a single token.
+fn read_token(lexer: &mut Lexer, token: Token) -> Result<Mal> {
+    use self::TokenKind::*;
     Ok(match token.kind {
-        ParOpen => {
-            read_list(lexer, token.start)?.into()
-        }
-        BrackOpen => {
-            read_arr(lexer, token.start)?.into()
-        }
-        CurlOpen => {
-            read_hash_map(lexer)?
-        }
         Ident(ident) => {
-            let atom = read_atom(ident)?;
-            atom
-        }
-        Apo => {
-            let quoted = read_form(lexer)?;
-            list_with_sym!["quote", quoted].into()
-        }
-        BackTick => {
-            let quoted = read_form(lexer)?;
-            list_with_sym!["quasiquote", quoted].into()
-        }
-        Tilde => {
-            let unquoted = read_form(lexer)?;
-            list_with_sym!["unquote", unquoted].into()
-        }
-        Tadpole => {
-            let unquoted = read_form(lexer)?;
-            list_with_sym!["splice-unquote", unquoted].into()
-        }
-        At => {
-            let derefed = read_form(lexer)?;
-            list_with_sym!["deref", derefed].into()
+            read_atom(ident)?
         }
         Str(string) => {
             Mal::Str(string)
         }
-        Hat => {
-            let meta = read_form(lexer)?;
-            let target = read_form(lexer)?;
-            list_with_sym!["with-meta", target, meta].into()
+        Char(ch) => {
+            Mal::Char(ch)
+        }
+        Regex(pattern) => {
+            match MalRegex::new(&pattern) {
+                Ok(regex) => regex.into(),
+                Err(e) => {
+                    return Err(ErrorKind::Reader {
+                        pos: token.start,
+                        source: String::from(lexer.text),
+                        msg: e.to_string(),
+                    }.into());
+                }
+            }
         }
         other => {
             return Err(ErrorKind::Reader {
@@ -382,3 +594,17 @@ pub fn read_form(lexer: &mut Lexer) -> Result<Mal> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_escapes_take_only_hex_digits() {
+        assert_eq!(read_str(r#""A\u{1F600}""#).unwrap(), Mal::from("A\u{1F600}".to_string()));
+        assert_eq!(read_str(r"\u0041").unwrap(), Mal::Char('A'));
+        for text in &[r#""\u+041""#, r#""\u{+41}""#, r#""\u{}""#, r"\u+041", r#"#bytes "+1""#] {
+            assert!(read_str(text).is_err(), "{} should not be read", text);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! The line editing of the REPLs: a prompt, Ctrl-C to cancel a line,
+//! Ctrl-D to quit, and a history that is kept in `~/.mal_history`.
+//!
+//! The history is loaded when the `Repl` is made, and saved when it is
+//! dropped, so a history file that can't be written is only warned about
+//! once.
+//!
+//! `configure` sets up the interpreter of a REPL the same way for every
+//! step, including the `--sandbox` flag.
+use std::env;
+use std::path::PathBuf;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use interpreter::Interpreter;
+use sandbox::Sandbox;
+use errors::*;
+
+/// How many items of a lazy sequence a REPL prints.
+pub const PRINT_LENGTH: usize = 100;
+
+/// Limits printing to `PRINT_LENGTH` items, and applies the sandbox of
+/// `--sandbox` if it is among the arguments, removing it from them.
+pub fn configure(interpreter: &mut Interpreter, args: &mut Vec<String>) {
+    interpreter.set_print_length(Some(PRINT_LENGTH));
+    if args.iter().any(|arg| arg == "--sandbox") {
+        args.retain(|arg| arg != "--sandbox");
+        let sandbox = sandbox(interpreter);
+        interpreter.set_sandbox(sandbox);
+    }
+}
+
+/// What `--sandbox` allows: reading files in the current directory and on
+/// the search path, and nothing else.
+fn sandbox(interpreter: &Interpreter) -> Sandbox {
+    let mut read_dirs = vec![PathBuf::from(".")];
+    read_dirs.extend(interpreter.env().modules().search_path());
+    Sandbox { read_dirs: Some(read_dirs), ..Sandbox::strict() }
+}
+
+/// Reads the lines typed at a REPL.
+pub struct Repl {
+    editor: DefaultEditor,
+    history: Option<PathBuf>,
+}
+
+/// Where the REPL keeps its history: `~/.mal_history`.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".mal_history"))
+}
+
+impl Repl {
+    pub fn new() -> Result<Repl> {
+        let mut editor = DefaultEditor::new().chain_err(|| "Could not start the REPL")?;
+        let history = history_path();
+        if let Some(ref path) = history {
+            // There is no history the first time.
+            let _ = editor.load_history(path);
+        }
+        Ok(Repl { editor, history })
+    }
+
+    /// Reads the next line that isn't blank, and adds it to the history.
+    /// Returns `None` at the end of the input, or when it can't be read.
+    pub fn read_line(&mut self, prompt: &str) -> Option<String> {
+        loop {
+            let input = match self.editor.readline(prompt) {
+                Ok(input) => input,
+                // Ctrl-C cancels the line, and Ctrl-D quits.
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return None,
+                Err(e) => {
+                    eprintln!("error: Could not read the input: {}", e);
+                    return None;
+                }
+            };
+            if input.trim().is_empty() {
+                continue;
+            }
+            let _ = self.editor.add_history_entry(input.as_str());
+            return Some(input);
+        }
+    }
+}
+
+impl Drop for Repl {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.history {
+            if let Err(e) = self.editor.save_history(path) {
+                eprintln!("warning: Could not save the history to '{}': {}", path.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! What the native functions may do outside of the interpreter: which
+//! files they may read and write, and whether they may start processes or
+//! look at environment variables.
+//!
+//! The sandbox of the `Interpreter` applies while it evaluates a form, and
+//! every native function that does I/O checks it first, with the `check_`
+//! functions here; natives added by the host should too. By default
+//! everything is allowed.
+//!
+//! The host reads the environment too, when it makes the interpreter:
+//! `MAL_PATH` sets the search path of `require`, but the files found on it
+//! are checked like any other.
+//!
+//! Paths are resolved, following symbolic links, before they are compared
+//! with the allowed directories, so a link can't lead out of them.
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use errors::*;
+
+/// What the native functions may access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    /// The directories files may be read from, with their subdirectories;
+    /// `None` means anywhere.
+    pub read_dirs: Option<Vec<PathBuf>>,
+    /// The directories files may be written to; `None` means anywhere.
+    pub write_dirs: Option<Vec<PathBuf>>,
+    /// Whether processes may be started.
+    pub allow_processes: bool,
+    /// Whether environment variables may be read.
+    pub allow_env: bool,
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::unrestricted()
+    }
+}
+
+impl Sandbox {
+    /// Allows everything.
+    pub fn unrestricted() -> Sandbox {
+        Sandbox { read_dirs: None, write_dirs: None, allow_processes: true, allow_env: true }
+    }
+
+    /// Allows nothing; open it up with the fields.
+    pub fn strict() -> Sandbox {
+        Sandbox {
+            read_dirs: Some(Vec::new()),
+            write_dirs: Some(Vec::new()),
+            allow_processes: false,
+            allow_env: false,
+        }
+    }
+}
+
+thread_local! {
+    static SANDBOX: RefCell<Rc<Sandbox>> = RefCell::new(Rc::new(Sandbox::unrestricted()));
+}
+
+/// Applies a sandbox until it is dropped, and then restores the one from
+/// before.
+pub(crate) struct Scope {
+    previous: Rc<Sandbox>,
+}
+
+impl Scope {
+    pub(crate) fn enter(sandbox: Rc<Sandbox>) -> Scope {
+        Scope { previous: SANDBOX.with(|current| current.replace(sandbox)) }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        SANDBOX.with(|current| *current.borrow_mut() = self.previous.clone());
+    }
+}
+
+fn current() -> Rc<Sandbox> {
+    SANDBOX.with(|current| current.borrow().clone())
+}
+
+fn denied<T>(action: String) -> Result<T> {
+    Err(ErrorKind::SandboxDenied(action).into())
+}
+
+/// Checks that the file may be read.
+pub fn check_read<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    if ! is_inside(path, &current().read_dirs) {
+        return denied(format!("reading '{}'", path.display()));
+    }
+    Ok(())
+}
+
+/// Checks that the file may be written, or made.
+pub fn check_write<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    if ! is_inside(path, &current().write_dirs) {
+        return denied(format!("writing '{}'", path.display()));
+    }
+    Ok(())
+}
+
+/// Checks that the command may be run.
+pub fn check_process(command: &str) -> Result<()> {
+    if ! current().allow_processes {
+        return denied(format!("running '{}'", command));
+    }
+    Ok(())
+}
+
+/// Checks that the environment variable may be read.
+pub fn check_env(name: &str) -> Result<()> {
+    if ! current().allow_env {
+        return denied(format!("reading the environment variable '{}'", name));
+    }
+    Ok(())
+}
+
+fn is_inside(path: &Path, dirs: &Option<Vec<PathBuf>>) -> bool {
+    let dirs = match *dirs {
+        Some(ref dirs) => dirs,
+        None => return true,
+    };
+    let path = match resolve(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    dirs.iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .any(|dir| path.starts_with(dir))
+}
+
+/// The absolute path without links, for a file that may not exist yet.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Ok(path),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            // A link to a file that doesn't exist could point anywhere.
+            if path.symlink_metadata().is_ok() {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            let name = match path.file_name() {
+                Some(name) if name != ".." => name,
+                _ => return Err(io::ErrorKind::NotFound.into()),
+            };
+            let parent = match path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+            Ok(parent.canonicalize()?.join(name))
+        }
+        Err(err) => Err(err),
+    }
+}
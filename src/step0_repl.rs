@@ -1,4 +1,7 @@
-use std::io::{self, Write, BufRead};
+extern crate mal;
+
+use mal::repl::Repl;
+use std::io::{self, Write};
 use std::env;
 
 fn read(text: &str) -> &str {
@@ -29,14 +32,17 @@ fn main() {
         return;
     }
     
-    let mut input = String::new();
-    loop {
-        input.clear();
-        print!("user> ");
-        let stdout = io::stdout();
-        stdout.lock().flush().unwrap();
-        let stdin = io::stdin();
-        stdin.lock().read_line(&mut input).unwrap();
-        print!("{}", rep(&input));
+    let mut repl = match Repl::new() {
+        Ok(repl) => repl,
+        Err(ref e) => {
+            eprintln!("error: {}", e);
+            for e in e.iter().skip(1) {
+                eprintln!("caused by: {}", e);
+            }
+            ::std::process::exit(1);
+        }
+    };
+    while let Some(input) = repl.read_line("user> ") {
+        println!("{}", rep(&input));
     }
 }
\ No newline at end of file
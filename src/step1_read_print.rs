@@ -1,7 +1,8 @@
 extern crate mal;
 
 use mal::{Mal};
-use std::io::{self, Write, BufRead};
+use mal::repl::Repl;
+use std::io::{self, Write};
 use std::env;
 
 fn read(text: &str) -> mal::Result<Mal> {
@@ -12,12 +13,12 @@ fn eval(expr: Mal) -> mal::Result<Mal> {
     Ok(expr)
 }
 
-fn print(mal: &Mal) -> String {
+fn print(mal: &Mal) -> mal::Result<String> {
     mal::pr_str(mal, true)
 }
 
 fn rep(text: &str) -> mal::Result<String> {
-    Ok(print(&eval(read(text)?)?))
+    print(&eval(read(text)?)?)
 }
 
 
@@ -59,17 +60,14 @@ fn main() {
         return;
     }
     
-    let mut input = String::new();
-    loop {
-        input.clear();
-        
-        print!("user> ");
-        let stdout = io::stdout();
-        stdout.lock().flush().unwrap();
-        
-        let stdin = io::stdin();
-        stdin.lock().read_line(&mut input).unwrap();
-        
+    let mut repl = match Repl::new() {
+        Ok(repl) => repl,
+        Err(ref e) => {
+            print_err(e);
+            ::std::process::exit(1);
+        }
+    };
+    while let Some(input) = repl.read_line("user> ") {
         match rep(&input) {
             Ok(string) => {
                 println!("{}", string);
@@ -81,4 +79,4 @@ fn main() {
             }
         }
     }
-}
+}
\ No newline at end of file
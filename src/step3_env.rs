@@ -1,28 +1,22 @@
 extern crate mal;
-#[macro_use]
-extern crate error_chain;
 
-mod eval;
-
-use mal::{Mal, Env};
-use std::io::{self, Write, BufRead};
+use mal::{Mal, Interpreter};
+use mal::repl::{self, Repl};
+use std::io::{self, Write};
 use std::env;
-use std::iter;
-use eval::eval;
 
 fn read(text: &str) -> mal::Result<Mal> {
     mal::read_str(text)
 }
 
-fn print(mal: &Mal) -> String {
-    mal::pr_str(mal, true)
+fn print(mal: &Mal, interpreter: &mut Interpreter) -> mal::Result<String> {
+    interpreter.print(mal, true)
 }
 
-fn rep(text: &str, env: &mut Env) -> mal::Result<String> {
-    let mut val = read(text)?;
-    eval(&mut val, env)?;
-    let text = print(&val);
-    Ok(text)
+fn rep(text: &str, interpreter: &mut Interpreter) -> mal::Result<String> {
+    let form = read(text)?;
+    let val = interpreter.eval(&form)?;
+    print(&val, interpreter)
 }
 
 
@@ -35,7 +29,7 @@ fn print_err(e: &mal::Error) {
     writeln!(stderr, "error: {}", e).expect(errmsg);
 
     for (i, e) in e.iter().skip(1).enumerate() {
-        write!(stderr, "{}", iter::repeat(" ").take(indent + i * indent).collect::<String>()).expect(errmsg);
+        write!(stderr, "{}", " ".repeat(indent + i * indent)).expect(errmsg);
         writeln!(stderr, "caused by: {}", e).expect(errmsg);
     }
 
@@ -47,13 +41,21 @@ fn print_err(e: &mal::Error) {
 }
 
 fn main() {
-    let mut env = mal::core_env();
+    let mut interpreter = match Interpreter::from_env() {
+        Ok(interpreter) => interpreter,
+        Err(ref e) => {
+            print_err(e);
+            ::std::process::exit(1);
+        }
+    };
     
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    repl::configure(&mut interpreter, &mut args);
+
     // If args are given, don't start in interactive mode.
-    let args = env::args().skip(1).collect::<Vec<_>>();
     if ! args.is_empty() {
         for arg in args {
-            match rep(&arg, &mut env) {
+            match rep(&arg, &mut interpreter) {
                 Ok(res) => {
                     println!("{}", res);
                     let stdout = io::stdout();
@@ -68,18 +70,15 @@ fn main() {
         return;
     }
     
-    let mut input = String::new();
-    loop {
-        input.clear();
-        
-        print!("user> ");
-        let stdout = io::stdout();
-        stdout.lock().flush().unwrap();
-        
-        let stdin = io::stdin();
-        stdin.lock().read_line(&mut input).unwrap();
-        
-        match rep(&input, &mut env) {
+    let mut repl = match Repl::new() {
+        Ok(repl) => repl,
+        Err(ref e) => {
+            print_err(e);
+            ::std::process::exit(1);
+        }
+    };
+    while let Some(input) = repl.read_line(&format!("{}> ", interpreter.env().ns().text())) {
+        match rep(&input, &mut interpreter) {
             Ok(string) => {
                 println!("{}", string);
                 let stdout = io::stdout();
@@ -90,4 +89,4 @@ fn main() {
             }
         }
     }
-}
+}
\ No newline at end of file
@@ -1,10 +1,21 @@
 use errors::*;
 use std::ops;
-use std::collections::{HashMap, VecDeque};
+use std::iter::FromIterator;
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::fmt;
 use std::clone;
 use std::cmp;
-use env::Env;
+use std::hash::{Hash, Hasher};
+use ast::Lambda;
+use gc;
+use limits;
+use im_rc::{HashMap, OrdMap, Vector};
+use im_rc::ordmap;
+use regex::Regex;
 
 #[derive(Debug, Clone)]
 pub enum Mal {
@@ -13,10 +24,16 @@ pub enum Mal {
     Num(f64),
     Sym(Symbol),
     Str(String),
+    Char(char),
+    Regex(MalRegex),
+    Bytes(Rc<[u8]>),
+    Lazy(LazySeq),
     Bool(bool),
     Kw(Keyword),
     Map(MalMap),
+    Set(MalSet),
     Fn(MalFunc),
+    Atom(MalAtom),
     Nil,
 }
 impl Mal {
@@ -28,10 +45,16 @@ impl Mal {
             Num(_) => "number",
             Sym(_) => "symbol",
             Str(_) => "string",
+            Char(_) => "char",
+            Regex(_) => "regex",
+            Bytes(_) => "bytes",
+            Lazy(_) => "lazy-seq",
             Bool(_) => "boolean",
             Kw(_) => "keyword",
             Map(_) => "hashmap",
+            Set(_) => "set",
             Fn(_) => "function",
+            Atom(_) => "atom",
             Nil => "nil",
          }
     }
@@ -50,7 +73,7 @@ impl Mal {
         }
     }
     
-    pub fn as_list_or_array(&mut self) -> Result<&mut VecDeque<Mal>> {
+    pub fn as_list_or_array(&mut self) -> Result<&mut Vector<Mal>> {
         match *self {
             Mal::List(ref mut list) => Ok(list.inner()),
             Mal::Arr(ref mut arr) => Ok(arr.inner()),
@@ -58,13 +81,34 @@ impl Mal {
         }
     }
     
-    pub fn as_function(&mut self) -> Result<&mut MalFunc> {
+    pub fn as_function(&self) -> Result<&MalFunc> {
         match *self {
-            Mal::Fn(ref mut func) => Ok(func),
+            Mal::Fn(ref func) => Ok(func),
             ref other => self.conv_err("function", other),
         }
     }
     
+    pub fn atom(&self) -> Result<&MalAtom> {
+        match *self {
+            Mal::Atom(ref atom) => Ok(atom),
+            ref other => self.conv_err("atom", other),
+        }
+    }
+    
+    pub fn string(&self) -> Result<&str> {
+        match *self {
+            Mal::Str(ref string) => Ok(string),
+            ref other => self.conv_err("string", other),
+        }
+    }
+    
+    pub fn bytes(&self) -> Result<&[u8]> {
+        match *self {
+            Mal::Bytes(ref bytes) => Ok(bytes),
+            ref other => self.conv_err("bytes", other),
+        }
+    }
+    
     pub fn list(self) -> Result<MalList> {
         match self {
             Mal::List(list) => Ok(list),
@@ -79,36 +123,396 @@ impl Mal {
         }
     }
     
-    pub fn is_truesy(&self) -> bool {
+    /// Returns the first item and the rest of a sequence, or `None` if it is
+    /// empty. Lazy sequences are realized one step, and the rest of a list
+    /// or array is a list sharing its items.
+    pub fn first_rest(&self) -> Result<Option<(Mal, Mal)>> {
+        let items = match *self {
+            Mal::List(ref list) => &list.items,
+            Mal::Arr(ref arr) => &arr.items,
+            Mal::Lazy(ref lazy) => return lazy.step(),
+            Mal::Nil => return Ok(None),
+            Mal::Set(_) | Mal::Map(_) | Mal::Str(_) | Mal::Bytes(_) => {
+                let list: MalList = self.seq_items()?.into();
+                return Mal::from(list).first_rest();
+            }
+            ref other => return self.conv_err("sequence", other),
+        };
+        if items.is_empty() {
+            return Ok(None);
+        }
+        let mut rest = (**items).clone();
+        let first = rest.pop_front().unwrap();
+        Ok(Some((first, MalList::from(rest).into())))
+    }
+    
+    /// Returns all the items of a sequence or collection, realizing lazy
+    /// sequences fully. Maps give [key value] arrays, and strings give
+    /// their characters.
+    pub fn seq_items(&self) -> Result<Vector<Mal>> {
         match *self {
-            Mal::Nil => false,
-            Mal::Bool(false) => false,
-            _ => true,
+            Mal::List(ref list) => Ok((*list.items).clone()),
+            Mal::Arr(ref arr) => Ok((*arr.items).clone()),
+            Mal::Set(ref set) => Ok(set.iter().cloned().collect()),
+            Mal::Map(ref map) => {
+                Ok(map.iter()
+                    .map(|(k, v)| vec![k.clone(), v.clone()].into_iter().collect::<MalArr>().into())
+                    .collect())
+            }
+            Mal::Str(ref string) => Ok(string.chars().map(Mal::Char).collect()),
+            Mal::Bytes(ref bytes) => Ok(bytes.iter().map(|&byte| Mal::Num(byte as f64)).collect()),
+            Mal::Lazy(_) => {
+                let mut items = Vector::new();
+                let mut seq = self.clone();
+                while let Some((first, rest)) = seq.first_rest()? {
+                    items.push_back(first);
+                    limits::check_size(items.len())?;
+                    limits::tick()?;
+                    seq = rest;
+                }
+                Ok(items)
+            }
+            Mal::Nil => Ok(Vector::new()),
+            ref other => self.conv_err("sequence", other),
         }
     }
+    
+    pub fn is_truesy(&self) -> bool {
+        !matches!(*self, Mal::Nil | Mal::Bool(false))
+    }
 }
 impl cmp::PartialEq for Mal {
+    /// Nested collections are compared with a stack of their items rather
+    /// than by recursion, so that deeply nested ones can be compared too.
     fn eq(&self, other: &Mal) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            if ! a.eq_shallow(b, &mut pending) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Mal {
+    /// Compares the values, except for the items of lists, arrays and maps,
+    /// which are added to `pending` to compare instead.
+    fn eq_shallow<'a>(&'a self, other: &'a Mal, pending: &mut Vec<(&'a Mal, &'a Mal)>) -> bool {
         use self::Mal::*;
         match (self, other) {
-            (&List(ref list),    &Arr(ref arr)) => list.items == arr.items,
-            (&Arr(ref arr),      &List(ref list)) => arr.items == list.items,
+            (List(list), Arr(arr)) => eq_items(&list.items, &arr.items, pending),
+            (Arr(arr), List(list)) => eq_items(&arr.items, &list.items, pending),
             
-            (&List(ref val), &List(ref oval)) => val == oval,
-            (&Arr(ref val),  &Arr(ref oval))  => val == oval,
-            (&Num(ref val),  &Num(ref oval))  => val == oval,
-            (&Sym(ref val),  &Sym(ref oval))  => val == oval,
-            (&Str(ref val),  &Str(ref oval))  => val == oval,
-            (&Bool(ref val), &Bool(ref oval)) => val == oval,
-            (&Kw(ref val),   &Kw(ref oval))   => val == oval,
-            (&Map(ref val),  &Map(ref oval))  => val == oval,
-            (&Fn(ref val),   &Fn(ref oval))   => val == oval,
-            (&Nil, &Nil) => true,
+            // Lazy sequences are realized to compare them, and are never
+            // equal if that fails.
+            (Lazy(val), Lazy(oval)) if val.ptr_eq(oval) => true,
+            (Lazy(_), List(_)) | (Lazy(_), Arr(_)) | (Lazy(_), Lazy(_)) |
+            (List(_), Lazy(_)) | (Arr(_), Lazy(_)) => {
+                match (self.seq_items(), other.seq_items()) {
+                    (Ok(items), Ok(oitems)) => items == oitems,
+                    _ => false,
+                }
+            }
+            
+            (List(val), List(oval)) => eq_items(&val.items, &oval.items, pending),
+            (Arr(val),  Arr(oval))  => eq_items(&val.items, &oval.items, pending),
+            (Num(val),  Num(oval))  => num_eq(*val, *oval),
+            (Sym(val),  Sym(oval))  => val == oval,
+            (Str(val),  Str(oval))  => val == oval,
+            (Char(val), Char(oval)) => val == oval,
+            (Regex(val), Regex(oval)) => val == oval,
+            (Bytes(val), Bytes(oval)) => val == oval,
+            (Bool(val), Bool(oval)) => val == oval,
+            (Kw(val),   Kw(oval))   => val == oval,
+            (Map(val),  Map(oval))  => eq_entries(val, oval, pending),
+            (Set(val),  Set(oval))  => eq_entries(&val.items, &oval.items, pending),
+            (Fn(val),   Fn(oval))   => val == oval,
+            (Atom(val), Atom(oval)) => val == oval,
+            (Nil, Nil) => true,
             _ => false
         }
     }
 }
 
+/// Compares the lengths of the sequences, and adds their items to
+/// `pending` to compare.
+fn eq_items<'a>(items: &'a Rc<Vector<Mal>>, oitems: &'a Rc<Vector<Mal>>,
+        pending: &mut Vec<(&'a Mal, &'a Mal)>) -> bool {
+    if Rc::ptr_eq(items, oitems) {
+        return true;
+    }
+    if items.len() != oitems.len() {
+        return false;
+    }
+    pending.extend(items.iter().zip(oitems.iter()));
+    true
+}
+
+/// Like `MalMap::eq`, but adds the values to `pending` to compare.
+/// Keys that are collections are paired with the only key of `omap` that
+/// hashes the same, when there is one, and compared later too; looking
+/// them up would compare them by recursion.
+fn eq_entries<'a>(map: &'a MalMap, omap: &'a MalMap,
+        pending: &mut Vec<(&'a Mal, &'a Mal)>) -> bool {
+    use self::Mal::*;
+    if map.len() != omap.len() {
+        return false;
+    }
+    let mut by_hash: Option<HashMap<u64, Vec<(&Mal, &Mal)>>> = None;
+    for (key, value) in map.iter() {
+        if let List(_) | Arr(_) | Lazy(_) | Map(_) | Set(_) = *key {
+            let by_hash = by_hash.get_or_insert_with(|| {
+                let mut by_hash = HashMap::new();
+                for (okey, ovalue) in omap.iter() {
+                    by_hash.entry(hash_one(okey)).or_insert_with(Vec::new).push((okey, ovalue));
+                }
+                by_hash
+            });
+            match by_hash.get(&hash_one(key)).map(Vec::as_slice) {
+                Some(&[(okey, ovalue)]) => {
+                    pending.push((key, okey));
+                    pending.push((value, ovalue));
+                    continue;
+                }
+                None => return false,
+                Some(_) => {}
+            }
+        }
+        match omap.get(key) {
+            Some(ovalue) => pending.push((value, ovalue)),
+            None => return false,
+        }
+    }
+    true
+}
+
+impl cmp::Eq for Mal {}
+
+/// Numbers compare as IEEE floats, except that all NaNs are equal to each
+/// other. Together with `num_hash_bits` this makes every value usable as a
+/// hashmap key: `0.0` and `-0.0` are the same key, and so are all NaNs.
+#[inline]
+fn num_eq(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+/// Returns bits that are equal for numbers that are equal by `num_eq`.
+#[inline]
+fn num_hash_bits(num: f64) -> u64 {
+    if num == 0.0 {
+        0.0f64.to_bits()
+    } else if num.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        num.to_bits()
+    }
+}
+
+/// How many levels of nested collections are hashed.
+const MAX_HASH_DEPTH: usize = 32;
+
+thread_local! {
+    static HASH_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Hashes the tag of a collection, and its items with `hash_items` unless
+/// it is nested more than `MAX_HASH_DEPTH` levels deep. Equal values still
+/// hash the same way, and deeply nested keys don't take time and stack in
+/// proportion to their depth.
+fn hash_nested<H: Hasher, F: FnOnce(&mut H)>(tag: u8, state: &mut H, hash_items: F) {
+    tag.hash(state);
+    let depth = HASH_DEPTH.with(Cell::get);
+    if depth < MAX_HASH_DEPTH {
+        HASH_DEPTH.with(|hash_depth| hash_depth.set(depth + 1));
+        hash_items(state);
+        HASH_DEPTH.with(|hash_depth| hash_depth.set(depth));
+    }
+}
+
+/// Hashes a value on its own, for order-independent combination.
+fn hash_one<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Hash for Mal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use self::Mal::*;
+        match *self {
+            // Lists and arrays with the same items are equal, so they must
+            // hash the same way too.
+            List(ref list) => hash_nested(0, state, |state| hash_seq(&list.items, state)),
+            Arr(ref arr) => hash_nested(0, state, |state| hash_seq(&arr.items, state)),
+            Lazy(_) => hash_nested(0, state, |state| {
+                if let Ok(items) = self.seq_items() {
+                    hash_seq(&items, state);
+                }
+            }),
+            Num(num) => {
+                1u8.hash(state);
+                num_hash_bits(num).hash(state);
+            }
+            Sym(ref sym) => {
+                2u8.hash(state);
+                sym.hash(state);
+            }
+            Str(ref string) => {
+                3u8.hash(state);
+                string.hash(state);
+            }
+            Bool(val) => {
+                4u8.hash(state);
+                val.hash(state);
+            }
+            Kw(ref kw) => {
+                5u8.hash(state);
+                kw.hash(state);
+            }
+            Map(ref map) => hash_nested(6, state, |state| map.hash(state)),
+            Set(ref set) => hash_nested(7, state, |state| set.hash(state)),
+            Fn(ref func) => {
+                8u8.hash(state);
+                func.hash(state);
+            }
+            Char(ch) => {
+                9u8.hash(state);
+                ch.hash(state);
+            }
+            Regex(ref regex) => {
+                10u8.hash(state);
+                regex.hash(state);
+            }
+            Bytes(ref bytes) => {
+                11u8.hash(state);
+                bytes.hash(state);
+            }
+            Atom(ref atom) => {
+                13u8.hash(state);
+                atom.hash(state);
+            }
+            Nil => 12u8.hash(state),
+        }
+    }
+}
+
+impl Mal {
+    /// The position of the value's type in the ordering of values.
+    fn type_rank(&self) -> u8 {
+        use self::Mal::*;
+        match *self {
+            Nil => 0,
+            Bool(_) => 1,
+            Num(_) => 2,
+            Char(_) => 3,
+            Str(_) => 4,
+            Kw(_) => 5,
+            Sym(_) => 6,
+            List(_) | Arr(_) | Lazy(_) => 7,
+            Map(_) => 8,
+            Set(_) => 9,
+            Regex(_) => 10,
+            Bytes(_) => 11,
+            Fn(_) => 12,
+            Atom(_) => 13,
+        }
+    }
+}
+
+impl cmp::PartialOrd for Mal {
+    fn partial_cmp(&self, other: &Mal) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for Mal {
+    /// A total order over all values, used for the keys of sorted maps.
+    /// Values of different types are ordered by type, and NaN sorts after
+    /// every other number. Like `eq`, nested collections are compared with
+    /// a stack of their items rather than by recursion.
+    fn cmp(&self, other: &Mal) -> cmp::Ordering {
+        let mut pending = Vec::new();
+        let mut ordering = self.cmp_shallow(other, &mut pending);
+        while ordering == cmp::Ordering::Equal {
+            let (a, b) = match pending.last_mut() {
+                Some(&mut (ref items, ref oitems, ref mut i)) => {
+                    let pair = (items.get(*i).cloned(), oitems.get(*i).cloned());
+                    *i += 1;
+                    pair
+                }
+                None => break,
+            };
+            ordering = match (a, b) {
+                (Some(a), Some(b)) => a.cmp_shallow(&b, &mut pending),
+                // The sequences are equal as far as the shorter one goes.
+                (a, b) => {
+                    pending.pop();
+                    a.is_some().cmp(&b.is_some())
+                }
+            };
+        }
+        ordering
+    }
+}
+
+/// The items of two collections being compared, and how many of them are
+/// known to be equal.
+type CmpItems = (Rc<Vector<Mal>>, Rc<Vector<Mal>>, usize);
+
+impl Mal {
+    /// Compares the values, except for the items of collections of the
+    /// same kind, which are added to `pending` to compare instead.
+    fn cmp_shallow(&self, other: &Mal, pending: &mut Vec<CmpItems>) -> cmp::Ordering {
+        use self::Mal::*;
+        let (items, oitems) = match (self, other) {
+            (List(val), List(oval)) => (val.items.clone(), oval.items.clone()),
+            (List(val), Arr(oval))  => (val.items.clone(), oval.items.clone()),
+            (Arr(val),  List(oval)) => (val.items.clone(), oval.items.clone()),
+            (Arr(val),  Arr(oval))  => (val.items.clone(), oval.items.clone()),
+            (Lazy(_), List(_)) | (Lazy(_), Arr(_)) | (Lazy(_), Lazy(_)) |
+            (List(_), Lazy(_)) | (Arr(_), Lazy(_)) => {
+                (Rc::new(self.seq_items().unwrap_or_default()),
+                    Rc::new(other.seq_items().unwrap_or_default()))
+            }
+            (Map(val),  Map(oval))  => (val.sorted_entries(), oval.sorted_entries()),
+            (Set(val),  Set(oval))  => (val.items.sorted_entries(), oval.items.sorted_entries()),
+            (Num(val),  Num(oval))  => return num_cmp(*val, *oval),
+            (Sym(val),  Sym(oval))  => return val.cmp(oval),
+            (Str(val),  Str(oval))  => return val.cmp(oval),
+            (Char(val), Char(oval)) => return val.cmp(oval),
+            (Regex(val), Regex(oval)) => return val.cmp(oval),
+            (Bytes(val), Bytes(oval)) => return val.cmp(oval),
+            (Bool(val), Bool(oval)) => return val.cmp(oval),
+            (Kw(val),   Kw(oval))   => return val.cmp(oval),
+            (Fn(val),   Fn(oval))   => return val.cmp(oval),
+            (Atom(val), Atom(oval)) => return val.cmp(oval),
+            _ => return self.type_rank().cmp(&other.type_rank()),
+        };
+        if ! Rc::ptr_eq(&items, &oitems) {
+            pending.push((items, oitems, 0));
+        }
+        cmp::Ordering::Equal
+    }
+}
+
+/// Orders numbers consistently with `num_eq`.
+fn num_cmp(a: f64, b: f64) -> cmp::Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => cmp::Ordering::Equal,
+        (true, false) => cmp::Ordering::Greater,
+        (false, true) => cmp::Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap(),
+    }
+}
+
+fn hash_seq<H: Hasher>(items: &Vector<Mal>, state: &mut H) {
+    items.len().hash(state);
+    for item in items {
+        item.hash(state);
+    }
+}
+
 impl From<Symbol> for Mal {
     fn from(value: Symbol) -> Mal {
         Mal::Sym(value)
@@ -127,6 +531,30 @@ impl From<f64> for Mal {
     }
 }
 
+impl From<char> for Mal {
+    fn from(value: char) -> Mal {
+        Mal::Char(value)
+    }
+}
+
+impl From<Vec<u8>> for Mal {
+    fn from(value: Vec<u8>) -> Mal {
+        Mal::Bytes(value.into())
+    }
+}
+
+impl From<LazySeq> for Mal {
+    fn from(value: LazySeq) -> Mal {
+        Mal::Lazy(value)
+    }
+}
+
+impl From<MalRegex> for Mal {
+    fn from(value: MalRegex) -> Mal {
+        Mal::Regex(value)
+    }
+}
+
 impl From<MalList> for Mal {
     fn from(value: MalList) -> Mal {
         Mal::List(value)
@@ -139,6 +567,12 @@ impl From<MalMap> for Mal {
     }
 }
 
+impl From<MalSet> for Mal {
+    fn from(value: MalSet) -> Mal {
+        Mal::Set(value)
+    }
+}
+
 impl From<MalArr> for Mal {
     fn from(value: MalArr) -> Mal {
         Mal::Arr(value)
@@ -151,69 +585,207 @@ impl From<String> for Mal {
     }
 }
 
+impl From<Keyword> for Mal {
+    fn from(value: Keyword) -> Mal {
+        Mal::Kw(value)
+    }
+}
+
 impl From<MalFunc> for Mal {
     fn from(value: MalFunc) -> Mal {
         Mal::Fn(value)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+impl From<MalAtom> for Mal {
+    fn from(value: MalAtom) -> Mal {
+        Mal::Atom(value)
+    }
+}
+
+/// The fewest names to intern before unused ones are dropped.
+const MIN_INTERNED: usize = 1024;
+
+/// Every symbol and keyword name in use. Each name is allocated once, so
+/// symbols and keywords can be compared and hashed by pointer.
+struct Interned {
+    names: HashSet<Rc<str>>,
+    /// When to drop the names that nothing else uses any more.
+    prune_at: usize,
+}
+
+thread_local! {
+    static INTERNED: RefCell<Interned> = RefCell::new(Interned {
+        names: HashSet::new(),
+        prune_at: MIN_INTERNED,
+    });
+}
+
+fn intern(text: &str) -> Rc<str> {
+    INTERNED.with(|interned| {
+        let mut interned = interned.borrow_mut();
+        if let Some(name) = interned.names.get(text) {
+            return name.clone();
+        }
+        // A name that is only held here can't be compared with any more,
+        // so it can be made again later.
+        if interned.names.len() >= interned.prune_at {
+            interned.names.retain(|name| Rc::strong_count(name) > 1);
+            interned.prune_at = MIN_INTERNED.max(interned.names.len() * 2);
+        }
+        let name: Rc<str> = Rc::from(text);
+        interned.names.insert(name.clone());
+        name
+    })
+}
+
+/// An interned name. Equality and hashing are by pointer, while the
+/// order is by name.
+#[derive(Clone)]
+struct Name(Rc<str>);
+
+impl cmp::PartialEq for Name {
+    #[inline]
+    fn eq(&self, other: &Name) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl cmp::Eq for Name {}
+
+impl Hash for Name {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0.as_ptr() as usize).hash(state);
+    }
+}
+
+impl cmp::PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for Name {
+    fn cmp(&self, other: &Name) -> cmp::Ordering {
+        if self == other {
+            cmp::Ordering::Equal
+        } else {
+            self.0.cmp(&other.0)
+        }
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", &*self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Symbol {
-    pub(crate) inner: String,
+    inner: Name,
 }
 
 impl Symbol {
     #[inline]
-    pub fn new<S: Into<String>>(value: S) -> Symbol {
-        Symbol { inner: value.into() }
+    pub fn new<S: AsRef<str>>(value: S) -> Symbol {
+        Symbol { inner: Name(intern(value.as_ref())) }
     }
     
     #[inline]
     pub fn text(&self) -> &str {
-        &self.inner
+        &self.inner.0
     }
     
     #[inline]
     pub fn into_string(self) -> String {
-        self.inner
+        self.text().to_string()
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Keyword {
-    pub(crate) sym: String,
+    sym: Name,
 }
 
 impl Keyword {
     #[inline]
-    pub fn new<S: Into<String>>(symbol: S) -> Keyword {
-        Keyword { sym: symbol.into() }
+    pub fn new<S: AsRef<str>>(symbol: S) -> Keyword {
+        Keyword { sym: Name(intern(symbol.as_ref())) }
     }
     
     #[inline]
     pub fn symbol(&self) -> &str {
-        &self.sym
+        &self.sym.0
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// How deeply the drops of values held by values may recurse, before the
+/// rest are put off so that deeply nested values can't overflow the stack.
+const MAX_DROP_DEPTH: usize = 100;
+
+/// What a value being dropped held, which is only kept to be dropped.
+#[allow(dead_code)]
+enum Held {
+    Items(Vector<Mal>),
+    Entries(MapInner),
+    Value(Mal),
+    Values(Vec<Mal>),
+}
+
+thread_local! {
+    static DROP_DEPTH: Cell<usize> = const { Cell::new(0) };
+    static DEFERRED_DROPS: RefCell<Vec<Held>> = const { RefCell::new(Vec::new()) };
+    /// Put in place of the entries of a map being dropped.
+    static NO_ENTRIES: Rc<MapInner> = Rc::new(MapInner::Sorted(OrdMap::new()));
+}
+
+/// Drops what a value held, when the value is dropped. Past
+/// `MAX_DROP_DEPTH` it is put off instead, and dropped by the outermost
+/// drop once that is done.
+fn drop_held(held: Held) {
+    let depth = DROP_DEPTH.with(Cell::get);
+    if depth == MAX_DROP_DEPTH {
+        // Once the thread is exiting, it is dropped right away.
+        let mut held = Some(held);
+        let _ = DEFERRED_DROPS.try_with(|deferred| deferred.borrow_mut().push(held.take().unwrap()));
+        return;
+    }
+    DROP_DEPTH.with(|drop_depth| drop_depth.set(depth + 1));
+    drop(held);
+    if depth == 0 {
+        while let Some(held) = DEFERRED_DROPS.try_with(|deferred| deferred.borrow_mut().pop()).ok().flatten() {
+            drop(held);
+        }
+    }
+    DROP_DEPTH.with(|drop_depth| drop_depth.set(depth));
+}
+
+/// A persistent list. Clones share their items, and changes only copy the
+/// parts of the list they touch.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct MalList {
-    pub(crate) items: VecDeque<Mal>,
+    pub(crate) items: Rc<Vector<Mal>>,
 }
 impl MalList {
     #[inline]
     pub fn new() -> MalList {
-        MalList { items: VecDeque::new() }
+        MalList { items: Rc::new(Vector::new()) }
     }
     
     #[inline]
-    pub fn inner(&mut self) -> &mut VecDeque<Mal> {
-        &mut self.items
+    pub fn inner(&mut self) -> &mut Vector<Mal> {
+        Rc::make_mut(&mut self.items)
+    }
+    
+    pub(crate) fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.items)
     }
 }
 
 impl ops::Deref for MalList {
-    type Target = VecDeque<Mal>;
+    type Target = Vector<Mal>;
     
     fn deref(&self) -> &Self::Target {
         &self.items
@@ -222,29 +794,54 @@ impl ops::Deref for MalList {
 
 impl ops::DerefMut for MalList {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.items
+        Rc::make_mut(&mut self.items)
+    }
+}
+
+impl Drop for MalList {
+    fn drop(&mut self) {
+        if let Some(items) = Rc::get_mut(&mut self.items) {
+            drop_held(Held::Items(mem::take(items)));
+        }
+    }
+}
+
+impl From<Vector<Mal>> for MalList {
+    fn from(items: Vector<Mal>) -> MalList {
+        MalList { items: Rc::new(items) }
+    }
+}
+
+impl FromIterator<Mal> for MalList {
+    fn from_iter<I: IntoIterator<Item=Mal>>(iter: I) -> MalList {
+        MalList::from(iter.into_iter().collect::<Vector<Mal>>())
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A persistent array, sharing structure like `MalList`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub struct MalArr {
-    pub(crate) items: VecDeque<Mal>,
+    pub(crate) items: Rc<Vector<Mal>>,
 }
 
 impl MalArr {
     #[inline]
     pub fn new() -> MalArr {
-        MalArr { items: VecDeque::new() }
+        MalArr { items: Rc::new(Vector::new()) }
     }
     
     #[inline]
-    pub fn inner(&mut self) -> &mut VecDeque<Mal> {
-        &mut self.items
+    pub fn inner(&mut self) -> &mut Vector<Mal> {
+        Rc::make_mut(&mut self.items)
+    }
+    
+    pub(crate) fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.items)
     }
 }
 
 impl ops::Deref for MalArr {
-    type Target = VecDeque<Mal>;
+    type Target = Vector<Mal>;
     
     fn deref(&self) -> &Self::Target {
         &self.items
@@ -253,68 +850,646 @@ impl ops::Deref for MalArr {
 
 impl ops::DerefMut for MalArr {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.items
+        Rc::make_mut(&mut self.items)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum MapKey {
-    Str(String),
-    Kw(Keyword),
+impl Drop for MalArr {
+    fn drop(&mut self) {
+        if let Some(items) = Rc::get_mut(&mut self.items) {
+            drop_held(Held::Items(mem::take(items)));
+        }
+    }
 }
 
-impl From<String> for MapKey {
-    fn from(value: String) -> MapKey {
-        MapKey::Str(value)
+impl From<Vector<Mal>> for MalArr {
+    fn from(items: Vector<Mal>) -> MalArr {
+        MalArr { items: Rc::new(items) }
     }
 }
 
-impl From<Keyword> for MapKey {
-    fn from(value: Keyword) -> MapKey {
-        MapKey::Kw(value)
+impl FromIterator<Mal> for MalArr {
+    fn from_iter<I: IntoIterator<Item=Mal>>(iter: I) -> MalArr {
+        MalArr::from(iter.into_iter().collect::<Vector<Mal>>())
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A persistent map that iterates in a deterministic order: insertion order
+/// for plain hashmaps, and key order for sorted maps.
+#[derive(Debug, Clone)]
 pub struct MalMap {
-    pub(crate) inner: HashMap<MapKey, Mal>,
+    inner: Rc<MapInner>,
+}
+
+#[derive(Debug, Clone)]
+enum MapInner {
+    /// The entries are kept under increasing sequence numbers, so that
+    /// iterating over them follows insertion order.
+    Hashed {
+        index: HashMap<Mal, u64>,
+        entries: OrdMap<u64, (Mal, Mal)>,
+        next_seq: u64,
+    },
+    Sorted(OrdMap<Mal, Mal>),
+}
+
+/// Iterator over the entries of a `MalMap`.
+pub enum MapIter<'a> {
+    Hashed(ordmap::Values<'a, u64, (Mal, Mal)>),
+    Sorted(ordmap::Iter<'a, Mal, Mal>),
+}
+
+impl<'a> Iterator for MapIter<'a> {
+    type Item = (&'a Mal, &'a Mal);
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            MapIter::Hashed(ref mut iter) => iter.next().map(|(k, v)| (k, v)),
+            MapIter::Sorted(ref mut iter) => iter.next(),
+        }
+    }
 }
 
 impl MalMap {
+    /// Creates a map that iterates in insertion order.
     #[inline]
     pub fn new() -> MalMap {
-        MalMap { inner: HashMap::new() }
+        MalMap { 
+            inner: Rc::new(MapInner::Hashed { 
+                index: HashMap::new(), 
+                entries: OrdMap::new(), 
+                next_seq: 0,
+            })
+        }
     }
     
+    /// Creates a map that iterates in key order.
     #[inline]
-    pub fn insert<K: Into<MapKey>, V: Into<Mal>>(&mut self, key: K, value: V) -> Option<Mal> {
-        self.inner.insert(key.into(), value.into())
+    pub fn sorted() -> MalMap {
+        MalMap { inner: Rc::new(MapInner::Sorted(OrdMap::new())) }
+    }
+    
+    /// Creates an empty map of the same kind as this one.
+    pub fn empty_like(&self) -> MalMap {
+        if self.is_sorted() { MalMap::sorted() } else { MalMap::new() }
+    }
+    
+    #[inline]
+    pub fn is_sorted(&self) -> bool {
+        matches!(*self.inner, MapInner::Sorted(_))
+    }
+    
+    /// Inserts the value, keeping the position of an existing key.
+    pub fn insert<K: Into<Mal>, V: Into<Mal>>(&mut self, key: K, value: V) -> Option<Mal> {
+        let (key, value) = (key.into(), value.into());
+        match *Rc::make_mut(&mut self.inner) {
+            MapInner::Hashed { ref mut index, ref mut entries, ref mut next_seq } => {
+                if let Some(&seq) = index.get(&key) {
+                    entries.insert(seq, (key, value)).map(|(_, old)| old)
+                } else {
+                    let seq = *next_seq;
+                    *next_seq += 1;
+                    index.insert(key.clone(), seq);
+                    entries.insert(seq, (key, value));
+                    None
+                }
+            }
+            MapInner::Sorted(ref mut map) => map.insert(key, value),
+        }
+    }
+    
+    /// Removes the key, keeping the order of the remaining entries.
+    pub fn remove(&mut self, key: &Mal) -> Option<Mal> {
+        match *Rc::make_mut(&mut self.inner) {
+            MapInner::Hashed { ref mut index, ref mut entries, .. } => {
+                let seq = index.remove(key)?;
+                entries.remove(&seq).map(|(_, old)| old)
+            }
+            MapInner::Sorted(ref mut map) => map.remove(key),
+        }
+    }
+    
+    pub fn get(&self, key: &Mal) -> Option<&Mal> {
+        match *self.inner {
+            MapInner::Hashed { ref index, ref entries, .. } => {
+                let seq = index.get(key)?;
+                entries.get(seq).map(|(_, value)| value)
+            }
+            MapInner::Sorted(ref map) => map.get(key),
+        }
+    }
+    
+    #[inline]
+    pub fn contains_key(&self, key: &Mal) -> bool {
+        self.get(key).is_some()
+    }
+    
+    pub fn len(&self) -> usize {
+        match *self.inner {
+            MapInner::Hashed { ref index, .. } => index.len(),
+            MapInner::Sorted(ref map) => map.len(),
+        }
+    }
+    
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    pub fn iter(&self) -> MapIter<'_> {
+        match *self.inner {
+            MapInner::Hashed { ref entries, .. } => MapIter::Hashed(entries.values()),
+            MapInner::Sorted(ref map) => MapIter::Sorted(map.iter()),
+        }
+    }
+    
+    pub fn keys(&self) -> impl Iterator<Item=&Mal> {
+        self.iter().map(|(k, _)| k)
+    }
+    
+    pub fn values(&self) -> impl Iterator<Item=&Mal> {
+        self.iter().map(|(_, v)| v)
+    }
+    
+    pub(crate) fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.inner)
+    }
+}
+
+impl Default for MalMap {
+    fn default() -> MalMap {
+        MalMap::new()
+    }
+}
+
+impl Drop for MalMap {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.inner) != 1 {
+            return;
+        }
+        let inner = match NO_ENTRIES.try_with(Rc::clone) {
+            Ok(none) => mem::replace(&mut self.inner, none),
+            Err(_) => return,
+        };
+        if let Ok(inner) = Rc::try_unwrap(inner) {
+            drop_held(Held::Entries(inner));
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a MalMap {
+    type Item = (&'a Mal, &'a Mal);
+    type IntoIter = MapIter<'a>;
+    
+    fn into_iter(self) -> MapIter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for MalMap {
+    type Item = (Mal, Mal);
+    type IntoIter = Box<dyn Iterator<Item=(Mal, Mal)>>;
+    
+    fn into_iter(mut self) -> Self::IntoIter {
+        let inner = mem::replace(&mut self.inner, NO_ENTRIES.with(Rc::clone));
+        let inner = Rc::try_unwrap(inner).unwrap_or_else(|inner| (*inner).clone());
+        match inner {
+            MapInner::Hashed { entries, .. } => Box::new(entries.into_iter().map(|(_, entry)| entry)),
+            MapInner::Sorted(map) => Box::new(map.into_iter()),
+        }
+    }
+}
+
+impl cmp::PartialEq for MalMap {
+    /// Maps are equal when they hold the same entries, whatever their order.
+    fn eq(&self, other: &MalMap) -> bool {
+        self.len() == other.len() && 
+            self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl cmp::Eq for MalMap {}
+
+impl Hash for MalMap {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Equal maps may iterate in different orders, so the entries are
+        // combined commutatively.
+        let mut combined = 0u64;
+        for entry in self.iter() {
+            combined = combined.wrapping_add(hash_one(&entry));
+        }
+        self.len().hash(state);
+        combined.hash(state);
+    }
+}
+
+impl cmp::PartialOrd for MalMap {
+    fn partial_cmp(&self, other: &MalMap) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for MalMap {
+    /// Compares the entries in key order, so that it agrees with `eq`.
+    fn cmp(&self, other: &MalMap) -> cmp::Ordering {
+        Mal::Map(self.clone()).cmp(&Mal::Map(other.clone()))
+    }
+}
+
+impl MalMap {
+    /// The keys and values, one after the other, in key order.
+    fn sorted_entries(&self) -> Rc<Vector<Mal>> {
+        let mut entries = self.iter().collect::<Vec<_>>();
+        if ! self.is_sorted() {
+            entries.sort_by_key(|&(key, _)| key);
+        }
+        Rc::new(entries.into_iter().flat_map(|(key, value)| vec![key.clone(), value.clone()]).collect())
+    }
+}
+
+/// A persistent set that iterates in insertion order.
+/// The items are stored as the keys of a map.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct MalSet {
+    items: MalMap,
+}
+
+impl MalSet {
+    #[inline]
+    pub fn new() -> MalSet {
+        MalSet { items: MalMap::new() }
+    }
+    
+    /// Adds the item, returning whether it was new.
+    #[inline]
+    pub fn insert<V: Into<Mal>>(&mut self, item: V) -> bool {
+        self.items.insert(item, Mal::Nil).is_none()
+    }
+    
+    /// Removes the item, returning whether it was present.
+    #[inline]
+    pub fn remove(&mut self, item: &Mal) -> bool {
+        self.items.remove(item).is_some()
+    }
+    
+    #[inline]
+    pub fn contains(&self, item: &Mal) -> bool {
+        self.items.contains_key(item)
+    }
+    
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item=&Mal> {
+        self.items.keys()
+    }
+    
+    pub(crate) fn ref_count(&self) -> usize {
+        self.items.ref_count()
+    }
+}
+
+impl FromIterator<Mal> for MalSet {
+    fn from_iter<I: IntoIterator<Item=Mal>>(iter: I) -> MalSet {
+        let mut set = MalSet::new();
+        for item in iter {
+            set.insert(item);
+        }
+        set
+    }
+}
+
+impl IntoIterator for MalSet {
+    type Item = Mal;
+    type IntoIter = Box<dyn Iterator<Item=Mal>>;
+    
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.items.into_iter().map(|(item, _)| item))
     }
 }
 
-impl ops::Deref for MalMap {
-    type Target = HashMap<MapKey, Mal>;
+/// A compiled regular expression. Regexes are compared by their pattern.
+#[derive(Debug, Clone)]
+pub struct MalRegex {
+    regex: Regex,
+}
+
+impl MalRegex {
+    pub fn new(pattern: &str) -> Result<MalRegex> {
+        let regex = Regex::new(pattern)
+            .chain_err(|| format!("Invalid regex: #\"{}\"", pattern))?;
+        Ok(MalRegex { regex })
+    }
+    
+    #[inline]
+    pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+}
+
+impl ops::Deref for MalRegex {
+    type Target = Regex;
     
     fn deref(&self) -> &Self::Target {
-        &self.inner
+        &self.regex
     }
 }
 
-impl ops::DerefMut for MalMap {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+impl cmp::PartialEq for MalRegex {
+    fn eq(&self, other: &MalRegex) -> bool {
+        self.pattern() == other.pattern()
+    }
+}
+
+impl cmp::Eq for MalRegex {}
+
+impl Hash for MalRegex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pattern().hash(state);
+    }
+}
+
+impl cmp::PartialOrd for MalRegex {
+    fn partial_cmp(&self, other: &MalRegex) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for MalRegex {
+    fn cmp(&self, other: &MalRegex) -> cmp::Ordering {
+        self.pattern().cmp(other.pattern())
+    }
+}
+
+/// A mutable reference to a value, made by `atom`. Atoms are identities:
+/// two atoms are only equal if they are the same atom.
+#[derive(Debug, Clone)]
+pub struct MalAtom {
+    value: Rc<RefCell<Mal>>,
+}
+
+impl Drop for MalAtom {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.value) == 1 {
+            drop_held(Held::Value(self.value.replace(Mal::Nil)));
+        }
+    }
+}
+
+impl MalAtom {
+    /// Makes an atom, which the `gc` keeps track of.
+    pub fn new(value: Mal) -> MalAtom {
+        let atom = MalAtom { value: Rc::new(RefCell::new(value)) };
+        gc::register_atom(Rc::downgrade(&atom.value));
+        atom
+    }
+    
+    pub fn get(&self) -> Mal {
+        self.value.borrow().clone()
+    }
+    
+    pub fn set(&self, value: Mal) {
+        *self.value.borrow_mut() = value;
+    }
+    
+    pub(crate) fn upgrade(weak: &Weak<RefCell<Mal>>) -> Option<MalAtom> {
+        weak.upgrade().map(|value| MalAtom { value })
+    }
+    
+    pub(crate) fn addr(&self) -> usize {
+        Rc::as_ptr(&self.value) as usize
+    }
+    
+    pub(crate) fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.value)
+    }
+    
+    pub(crate) fn replace(&self, value: Mal) -> Mal {
+        self.value.replace(value)
+    }
+}
+
+impl cmp::PartialEq for MalAtom {
+    fn eq(&self, other: &MalAtom) -> bool {
+        Rc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+impl cmp::Eq for MalAtom {}
+
+impl Hash for MalAtom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr().hash(state);
+    }
+}
+
+impl cmp::PartialOrd for MalAtom {
+    fn partial_cmp(&self, other: &MalAtom) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for MalAtom {
+    fn cmp(&self, other: &MalAtom) -> cmp::Ordering {
+        self.addr().cmp(&other.addr())
+    }
+}
+
+/// The code that produces the next step of a lazy sequence. It returns nil,
+/// a list, an array or another lazy sequence. It is run again if it was
+/// interrupted or exceeded a limit.
+pub type Thunk = Rc<dyn Fn() -> Result<Mal>>;
+
+enum LazyState {
+    Pending(Thunk),
+    /// The thunk is running.
+    Realizing,
+    /// The thunk failed with the given error.
+    Failed(ErrorKind),
+    Empty,
+    Cons(Mal, Mal),
+}
+
+/// A sequence whose items are computed when they are first needed, and
+/// then remembered. Clones share the realized items.
+#[derive(Clone)]
+pub struct LazySeq {
+    state: Rc<RefCell<LazyState>>,
+}
+
+impl LazySeq {
+    /// Creates a lazy sequence that runs the thunk when it is first used.
+    pub fn new<F>(thunk: F) -> LazySeq where F: Fn() -> Result<Mal> + 'static {
+        LazySeq { state: Rc::new(RefCell::new(LazyState::Pending(Rc::new(thunk)))) }
+    }
+    
+    /// Creates an already realized sequence, without touching `rest`.
+    pub fn cons(first: Mal, rest: Mal) -> LazySeq {
+        LazySeq { state: Rc::new(RefCell::new(LazyState::Cons(first, rest))) }
+    }
+    
+    #[inline]
+    pub fn ptr_eq(&self, other: &LazySeq) -> bool {
+        Rc::ptr_eq(&self.state, &other.state)
+    }
+    
+    pub(crate) fn addr(&self) -> usize {
+        Rc::as_ptr(&self.state) as *const u8 as usize
+    }
+    
+    pub(crate) fn ref_count(&self) -> usize {
+        Rc::strong_count(&self.state)
+    }
+    
+    /// The first item and the rest, if they are realized already.
+    pub(crate) fn realized(&self) -> Option<(Mal, Mal)> {
+        match *self.state.borrow() {
+            LazyState::Cons(ref first, ref rest) => Some((first.clone(), rest.clone())),
+            _ => None,
+        }
+    }
+    
+    /// Realizes the first item of the sequence, if it isn't already.
+    pub fn step(&self) -> Result<Option<(Mal, Mal)>> {
+        let thunk = {
+            let mut state = self.state.borrow_mut();
+            match *state {
+                LazyState::Pending(_) => {
+                    match mem::replace(&mut *state, LazyState::Realizing) {
+                        LazyState::Pending(thunk) => thunk,
+                        _ => unreachable!(),
+                    }
+                }
+                LazyState::Realizing => bail!("Lazy sequence depends on itself"),
+                LazyState::Failed(ref kind) => return Err(kind.clone().into()),
+                LazyState::Empty => return Ok(None),
+                LazyState::Cons(ref first, ref rest) => {
+                    return Ok(Some((first.clone(), rest.clone())));
+                }
+            }
+        };
+        let step = thunk().and_then(|value| value.first_rest());
+        *self.state.borrow_mut() = match step {
+            Ok(Some((ref first, ref rest))) => LazyState::Cons(first.clone(), rest.clone()),
+            Ok(None) => LazyState::Empty,
+            Err(ref e) if limits::interrupted() || limits::is_exceeded(e) => {
+                LazyState::Pending(thunk)
+            }
+            Err(ref e) => LazyState::Failed(e.kind().clone()),
+        };
+        step
+    }
+    
+    /// Takes the rest of the sequence out if nothing else refers to this
+    /// part of it, so that long sequences can be dropped without recursion.
+    fn take_unique_rest(&mut self) -> Option<LazySeq> {
+        if Rc::strong_count(&self.state) != 1 {
+            return None;
+        }
+        match *self.state.borrow_mut() {
+            LazyState::Cons(_, ref mut rest @ Mal::Lazy(_)) => {
+                match mem::replace(rest, Mal::Nil) {
+                    Mal::Lazy(lazy) => Some(lazy),
+                    _ => unreachable!(),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Drop for LazySeq {
+    fn drop(&mut self) {
+        let mut next = self.take_unique_rest();
+        while let Some(mut lazy) = next {
+            next = lazy.take_unique_rest();
+        }
+        if Rc::strong_count(&self.state) == 1 {
+            let first = match *self.state.borrow_mut() {
+                LazyState::Cons(ref mut first, _) => mem::replace(first, Mal::Nil),
+                _ => return,
+            };
+            drop_held(Held::Value(first));
+        }
+    }
+}
+
+impl fmt::Debug for LazySeq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.state.borrow() {
+            LazyState::Cons(ref first, ref rest) => {
+                write!(f, "LazySeq::Cons({:?}, {:?})", first, rest)
+            }
+            LazyState::Empty => write!(f, "LazySeq::Empty"),
+            LazyState::Failed(ref msg) => write!(f, "LazySeq::Failed({:?})", msg),
+            _ => write!(f, "LazySeq::Pending"),
+        }
     }
 }
 
 pub type NativeFunc = fn(&mut MalList) -> Result<Mal>;
 
+/// One parameter list of a closure, and the body run when it is chosen.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Arity {
+    /// Parameter patterns, possibly ending in `& rest`.
+    pub params: MalArr,
+    pub body: Mal,
+}
+
+impl Arity {
+    /// Returns the number of fixed parameters, and whether more are taken.
+    pub fn required(&self) -> (usize, bool) {
+        match self.params.iter().position(|param| *param == Symbol::new("&").into()) {
+            Some(nargs) => (nargs, true),
+            None => (self.params.len(), false),
+        }
+    }
+    
+    pub fn accepts(&self, nargs: usize) -> bool {
+        match self.required() {
+            (required, true) => nargs >= required,
+            (required, false) => nargs == required,
+        }
+    }
+}
+
 pub enum MalFunc {
     Native(&'static str, NativeFunc),
-    /// args, closed environment, body
-    Closure(VecDeque<Symbol>, Env, Box<Mal>),
-    /// name, args, closed env, body
-    /// What would be a 'function' in another language.
-    NamedClosure(Symbol, VecDeque<Symbol>, Env, Box<Mal>),
+    Closure(Rc<Closure>),
+}
+
+/// A function made by `fn*`: its analyzed code and the values it
+/// captured from the functions around it.
+pub struct Closure {
+    pub lambda: Rc<Lambda>,
+    pub captures: Vec<Mal>,
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        drop_held(Held::Values(mem::take(&mut self.captures)));
+    }
+}
+
+impl Closure {
+    /// The name it was defined with by `def!`.
+    pub fn name(&self) -> Option<&Symbol> {
+        self.lambda.name.as_ref()
+    }
+    
+    /// The arities as written.
+    pub fn arities(&self) -> &[Arity] {
+        &self.lambda.source
+    }
 }
 
 impl fmt::Debug for MalFunc {
@@ -324,11 +1499,11 @@ impl fmt::Debug for MalFunc {
             Native(name, _) => {
                 write!(f, "MalFunc::Native {{ \"{}\" }}", name)
             }
-            Closure(ref args, ref _env, ref body) => {
-                write!(f, "MalFunc::Closure {{ ({:?}) => {:?} }}", args, body)
-            }
-            NamedClosure(ref name, ref args, ref _env, ref body) => {
-                write!(f, "MalFunc::NamedClosure {{ {}({:?}) => {:?} }}", name.text(), args, body)
+            Closure(ref closure) => {
+                match closure.name() {
+                    Some(name) => write!(f, "MalFunc::Closure {{ {} {:?} }}", name.text(), closure.arities()),
+                    None => write!(f, "MalFunc::Closure {{ {:?} }}", closure.arities()),
+                }
             }
         }
     }
@@ -340,11 +1515,8 @@ impl clone::Clone for MalFunc {
             MalFunc::Native(name, func) => {
                 MalFunc::Native(name, func)
             }
-            MalFunc::Closure(ref args, ref env, ref body) => {
-                MalFunc::Closure(args.clone(), env.clone(), body.clone())
-            }
-            MalFunc::NamedClosure(ref name, ref args, ref env, ref body) => {
-                MalFunc::NamedClosure(name.clone(), args.clone(), env.clone(), body.clone())
+            MalFunc::Closure(ref closure) => {
+                MalFunc::Closure(closure.clone())
             }
         }
     }
@@ -354,17 +1526,78 @@ impl cmp::PartialEq for MalFunc {
     fn eq(&self, other: &MalFunc) -> bool {
         use self::MalFunc::*;
         match (self, other) {
-            (&Native(name, _), &Native(oname, _)) => {
+            (Native(name, _), Native(oname, _)) => {
                 oname == name
             }
-            (&Closure(ref args, ref env, ref body), &Closure(ref oargs, ref oenv, ref obody)) => {
-                oargs == args && obody == body && oenv == env
-            }
-            (&NamedClosure(ref name, ref args, ref env, ref body), &NamedClosure(ref oname, ref oargs, ref oenv, ref obody)) => {
-                oname == name && oargs == args && obody == body && oenv == env
+            (Closure(closure), Closure(oclosure)) => {
+                Rc::ptr_eq(closure, oclosure) || (
+                    closure.name() == oclosure.name() &&
+                    closure.arities() == oclosure.arities() &&
+                    closure.captures == oclosure.captures)
             }
             _ => false,
         }
     }
 }
 
+impl cmp::Eq for MalFunc {}
+
+impl Hash for MalFunc {
+    /// The captured values are left out; equal functions still hash equally.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use self::MalFunc::*;
+        match *self {
+            Native(name, _) => {
+                0u8.hash(state);
+                name.hash(state);
+            }
+            Closure(ref closure) => {
+                1u8.hash(state);
+                closure.name().hash(state);
+                closure.arities().hash(state);
+            }
+        }
+    }
+}
+
+impl cmp::PartialOrd for MalFunc {
+    fn partial_cmp(&self, other: &MalFunc) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl cmp::Ord for MalFunc {
+    fn cmp(&self, other: &MalFunc) -> cmp::Ordering {
+        use self::MalFunc::*;
+        match (self, other) {
+            (Native(name, _), Native(oname, _)) => name.cmp(oname),
+            (Native(..), _) => cmp::Ordering::Less,
+            (_, Native(..)) => cmp::Ordering::Greater,
+            (Closure(closure), Closure(oclosure)) => {
+                (closure.name(), closure.arities(), &closure.captures)
+                    .cmp(&(oclosure.name(), oclosure.arities(), &oclosure.captures))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interned() -> usize {
+        INTERNED.with(|interned| interned.borrow().names.len())
+    }
+
+    #[test]
+    fn unused_names_are_dropped() {
+        let kept = Symbol::new("kept-name");
+        for i in 0..100_000 {
+            Symbol::new(format!("sym-{}", i));
+            Keyword::new(format!("kw-{}", i));
+        }
+        assert!(interned() <= 2 * MIN_INTERNED, "{} names are interned", interned());
+        assert_eq!(Symbol::new("kept-name"), kept);
+        assert_eq!(Keyword::new("kept-name").symbol(), kept.text());
+    }
+}
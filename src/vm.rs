@@ -0,0 +1,454 @@
+//! A bytecode compiler, and the stack machine that runs its code.
+//!
+//! The compiler works on the nodes of the analyzer, so locals and upvalues
+//! are already slots by the time they get here, and each arity of a
+//! function becomes a chunk of its own. Frames, closures and destructuring
+//! are shared with the tree evaluator, so closures made by either engine
+//! can call each other.
+use std::mem;
+use std::rc::Rc;
+use types::{Mal, MalArr, MalMap, MalSet, MalFunc, LazySeq, Symbol};
+use ast::{Node, Lambda, Body, Pattern, MatchPattern};
+use env::{Env, Var};
+use eval::{self, Frame, Flow};
+use destructure;
+use limits;
+use printer;
+use errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Push a constant.
+    Const(usize),
+    Nil,
+    Local(usize),
+    Upvalue(usize),
+    SelfRef,
+    Global(usize),
+    Pop,
+    Dup,
+    Jump(usize),
+    /// Pop a value, and jump if it is false or nil.
+    JumpIfFalse(usize),
+    /// Pop a value and bind it to a pattern. The text names the form in errors.
+    Bind(usize, &'static str),
+    /// Pop the values of a loop's bindings, bind them again and jump back.
+    RecurLoop(usize, usize),
+    /// Pop the arguments of a `recur` to the running function, which binds
+    /// them and starts the chunk again.
+    Recur(usize),
+    /// Set a global to the value on top of the stack.
+    Def(usize),
+    /// Bind the value on top of the stack to a pattern, and set the
+    /// globals it binds.
+    DefPattern(usize, usize),
+    /// Match the value on top of the stack, or jump to the next clause.
+    Match(usize, usize),
+    NoMatch,
+    Closure(usize),
+    LazySeq(usize),
+    Vector(usize),
+    /// Pop the given number of key and value pairs.
+    Map(usize),
+    Set(usize),
+    /// Run a namespace form with the tree evaluator.
+    Special(usize),
+    Call(usize),
+    TailCall(usize),
+    Return,
+}
+
+/// The code of a top-level form or of one arity of a function.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    ops: Vec<Op>,
+    consts: Vec<Mal>,
+    globals: Vec<(Var, Symbol)>,
+    patterns: Vec<Pattern>,
+    /// The patterns bound by each loop.
+    loop_patterns: Vec<Vec<usize>>,
+    match_patterns: Vec<MatchPattern>,
+    def_vars: Vec<Vec<(Var, usize)>>,
+    lambdas: Vec<Rc<Lambda>>,
+    specials: Vec<Node>,
+    /// The globals that `def!` sets.
+    env: Option<Env>,
+}
+
+impl Chunk {
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+}
+
+/// Compiles an analyzed form, and the functions in it.
+pub fn compile(node: Node) -> Chunk {
+    let mut compiler = Compiler::default();
+    compiler.node(node);
+    compiler.emit(Op::Return);
+    compiler.chunk
+}
+
+/// Compiles the arities of a lambda that are still trees.
+fn compile_lambda(mut lambda: Rc<Lambda>) -> Rc<Lambda> {
+    // A lambda is only shared once closures are made of it, and by then
+    // it is compiled.
+    if let Some(lambda) = Rc::get_mut(&mut lambda) {
+        for arity in &mut lambda.arities {
+            arity.body = match mem::replace(&mut arity.body, Body::Code(Chunk::default())) {
+                Body::Tree(node) => Body::Code(compile(node)),
+                code => code,
+            };
+        }
+    }
+    lambda
+}
+
+/// A loop whose body is being compiled.
+struct LoopTarget {
+    patterns: usize,
+    start: usize,
+}
+
+#[derive(Default)]
+struct Compiler {
+    chunk: Chunk,
+    loops: Vec<LoopTarget>,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.ops.push(op);
+        self.chunk.ops.len() - 1
+    }
+
+    /// Points the jump at the given index to the next op.
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.ops.len();
+        self.chunk.ops[at] = match self.chunk.ops[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::Match(pattern, _) => Op::Match(pattern, target),
+            op => unreachable!("{:?} is not a jump", op),
+        };
+    }
+
+    fn pattern(&mut self, pattern: Pattern) -> usize {
+        self.chunk.patterns.push(pattern);
+        self.chunk.patterns.len() - 1
+    }
+
+    fn nodes(&mut self, nodes: Vec<Node>) -> usize {
+        let len = nodes.len();
+        for node in nodes {
+            self.node(node);
+        }
+        len
+    }
+
+    fn set_env(&mut self, env: Env) {
+        if self.chunk.env.is_none() {
+            self.chunk.env = Some(env);
+        }
+    }
+
+    fn node(&mut self, node: Node) {
+        match node {
+            Node::Const(Mal::Nil) => {
+                self.emit(Op::Nil);
+            }
+            Node::Const(value) => {
+                self.chunk.consts.push(value);
+                let i = self.chunk.consts.len() - 1;
+                self.emit(Op::Const(i));
+            }
+            Node::Local(slot) => {
+                self.emit(Op::Local(slot));
+            }
+            Node::Upvalue(i) => {
+                self.emit(Op::Upvalue(i));
+            }
+            Node::SelfRef => {
+                self.emit(Op::SelfRef);
+            }
+            Node::Global(var, sym) => {
+                self.chunk.globals.push((var, sym));
+                let i = self.chunk.globals.len() - 1;
+                self.emit(Op::Global(i));
+            }
+            Node::Vector(nodes) => {
+                let len = self.nodes(nodes);
+                self.emit(Op::Vector(len));
+            }
+            Node::Map(entries) => {
+                let len = entries.len();
+                for (key, value) in entries {
+                    self.node(key);
+                    self.node(value);
+                }
+                self.emit(Op::Map(len));
+            }
+            Node::Set(nodes) => {
+                let len = self.nodes(nodes);
+                self.emit(Op::Set(len));
+            }
+            Node::If(condition, then, otherwise) => {
+                self.node(*condition);
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.node(*then);
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_else);
+                match otherwise {
+                    Some(otherwise) => self.node(*otherwise),
+                    None => {
+                        self.emit(Op::Nil);
+                    }
+                }
+                self.patch(to_end);
+            }
+            Node::Do(nodes) => {
+                let len = nodes.len();
+                for (i, node) in nodes.into_iter().enumerate() {
+                    self.node(node);
+                    if i + 1 != len {
+                        self.emit(Op::Pop);
+                    }
+                }
+            }
+            Node::Let(bindings, body) => {
+                for (pattern, value) in bindings {
+                    self.node(value);
+                    let pattern = self.pattern(pattern);
+                    self.emit(Op::Bind(pattern, "let*: Invalid binding"));
+                }
+                self.node(*body);
+            }
+            Node::Loop(bindings, body) => {
+                let mut patterns = Vec::new();
+                for (pattern, value) in bindings {
+                    self.node(value);
+                    let pattern = self.pattern(pattern);
+                    self.emit(Op::Bind(pattern, "loop: Invalid binding"));
+                    patterns.push(pattern);
+                }
+                self.chunk.loop_patterns.push(patterns);
+                let target = LoopTarget {
+                    patterns: self.chunk.loop_patterns.len() - 1,
+                    start: self.chunk.ops.len(),
+                };
+                self.loops.push(target);
+                self.node(*body);
+                self.loops.pop();
+            }
+            Node::Recur(nodes) => {
+                let len = self.nodes(nodes);
+                match self.loops.last() {
+                    Some(target) => {
+                        let op = Op::RecurLoop(target.patterns, target.start);
+                        self.emit(op);
+                    }
+                    None => {
+                        self.emit(Op::Recur(len));
+                    }
+                }
+            }
+            Node::Fn(lambda) => {
+                self.chunk.lambdas.push(compile_lambda(lambda));
+                let i = self.chunk.lambdas.len() - 1;
+                self.emit(Op::Closure(i));
+            }
+            Node::LazySeq(lambda) => {
+                self.chunk.lambdas.push(compile_lambda(lambda));
+                let i = self.chunk.lambdas.len() - 1;
+                self.emit(Op::LazySeq(i));
+            }
+            Node::Def(env, var, value) => {
+                self.set_env(env);
+                self.node(*value);
+                let sym = var.name().clone();
+                self.chunk.globals.push((var, sym));
+                let i = self.chunk.globals.len() - 1;
+                self.emit(Op::Def(i));
+            }
+            Node::DefPattern(env, pattern, vars, value) => {
+                self.set_env(env);
+                self.node(*value);
+                let pattern = self.pattern(pattern);
+                self.chunk.def_vars.push(vars);
+                let vars = self.chunk.def_vars.len() - 1;
+                self.emit(Op::DefPattern(pattern, vars));
+            }
+            Node::DefLocal(pattern, value) => {
+                self.node(*value);
+                self.emit(Op::Dup);
+                let pattern = self.pattern(pattern);
+                self.emit(Op::Bind(pattern, "def!: Invalid binding"));
+            }
+            Node::Match(value, clauses) => {
+                self.node(*value);
+                let mut to_end = Vec::new();
+                for clause in clauses {
+                    self.chunk.match_patterns.push(clause.pattern);
+                    let pattern = self.chunk.match_patterns.len() - 1;
+                    let to_next = self.emit(Op::Match(pattern, 0));
+                    let guard_to_next = clause.guard.map(|guard| {
+                        self.node(guard);
+                        self.emit(Op::JumpIfFalse(0))
+                    });
+                    self.emit(Op::Pop);
+                    self.node(clause.body);
+                    to_end.push(self.emit(Op::Jump(0)));
+                    self.patch(to_next);
+                    if let Some(guard_to_next) = guard_to_next {
+                        self.patch(guard_to_next);
+                    }
+                }
+                self.emit(Op::NoMatch);
+                for at in to_end {
+                    self.patch(at);
+                }
+            }
+            special @ Node::Ns(..) | special @ Node::InNs(..) | special @ Node::Require(..) |
+            special @ Node::LoadFile(..) => {
+                self.chunk.specials.push(special);
+                let i = self.chunk.specials.len() - 1;
+                self.emit(Op::Special(i));
+            }
+            Node::Call { func, args, tail } => {
+                self.node(*func);
+                let len = self.nodes(args);
+                self.emit(if tail { Op::TailCall(len) } else { Op::Call(len) });
+            }
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Mal>) -> Mal {
+    stack.pop().expect("VM stack underflow")
+}
+
+fn pop_n(stack: &mut Vec<Mal>, n: usize) -> Vec<Mal> {
+    let at = stack.len().checked_sub(n).expect("VM stack underflow");
+    stack.split_off(at)
+}
+
+fn top(stack: &[Mal]) -> &Mal {
+    stack.last().expect("VM stack underflow")
+}
+
+/// Runs a chunk until it returns a value, makes a tail call or recurs to
+/// the running function.
+pub(crate) fn run(chunk: &Chunk, frame: &mut Frame) -> Result<Flow> {
+    let mut stack: Vec<Mal> = Vec::new();
+    let mut pc = 0;
+    loop {
+        let op = chunk.ops[pc];
+        pc += 1;
+        match op {
+            Op::Const(i) => stack.push(chunk.consts[i].clone()),
+            Op::Nil => stack.push(Mal::Nil),
+            Op::Local(slot) => stack.push(frame.slots[slot].clone()),
+            Op::Upvalue(i) => stack.push(frame.closure().captures[i].clone()),
+            Op::SelfRef => stack.push(MalFunc::Closure(frame.closure().clone()).into()),
+            Op::Global(i) => {
+                let (ref var, ref sym) = chunk.globals[i];
+                match var.get() {
+                    Some(value) => stack.push(value),
+                    None => bail!("Unknown variable: '{}'", sym.text()),
+                }
+            }
+            Op::Pop => {
+                pop(&mut stack);
+            }
+            Op::Dup => {
+                let value = top(&stack).clone();
+                stack.push(value);
+            }
+            Op::Jump(target) => pc = target,
+            Op::JumpIfFalse(target) => {
+                if ! pop(&mut stack).is_truesy() {
+                    pc = target;
+                }
+            }
+            Op::Bind(pattern, form) => {
+                let value = pop(&mut stack);
+                destructure::bind(&chunk.patterns[pattern], value, frame)
+                    .chain_err(|| form)?;
+            }
+            Op::RecurLoop(patterns, start) => {
+                limits::step()?;
+                let patterns = &chunk.loop_patterns[patterns];
+                let values = pop_n(&mut stack, patterns.len());
+                for (&pattern, value) in patterns.iter().zip(values) {
+                    destructure::bind(&chunk.patterns[pattern], value, frame)
+                        .chain_err(|| "recur: Invalid arguments")?;
+                }
+                pc = start;
+            }
+            Op::Recur(nargs) => return Ok(Flow::Recur(pop_n(&mut stack, nargs))),
+            Op::Def(i) => {
+                let env = chunk.env.as_ref().expect("def! without an environment");
+                env.set_var(&chunk.globals[i].0, top(&stack).clone());
+            }
+            Op::DefPattern(pattern, vars) => {
+                let env = chunk.env.as_ref().expect("def! without an environment");
+                let value = top(&stack).clone();
+                destructure::bind(&chunk.patterns[pattern], value, frame)
+                    .chain_err(|| "def!: Invalid binding")?;
+                for (var, slot) in &chunk.def_vars[vars] {
+                    env.set_var(var, frame.slots[*slot].clone());
+                }
+            }
+            Op::Match(pattern, next) => {
+                let pattern = &chunk.match_patterns[pattern];
+                if ! destructure::match_pattern(pattern, top(&stack), frame)? {
+                    pc = next;
+                }
+            }
+            Op::NoMatch => {
+                bail!("match: No clause matched {}", printer::pr_str(top(&stack), true)?);
+            }
+            Op::Closure(i) => {
+                let closure = eval::make_closure(&chunk.lambdas[i], frame);
+                stack.push(MalFunc::Closure(closure).into());
+            }
+            Op::LazySeq(i) => {
+                let closure = eval::make_closure(&chunk.lambdas[i], frame);
+                stack.push(LazySeq::new(move || eval::call_closure(closure.clone(), Vec::new())).into());
+            }
+            Op::Vector(len) => {
+                let items = pop_n(&mut stack, len);
+                stack.push(items.into_iter().collect::<MalArr>().into());
+            }
+            Op::Map(len) => {
+                let mut map = MalMap::new();
+                let mut items = pop_n(&mut stack, len * 2).into_iter();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    map.insert(key, value);
+                }
+                stack.push(map.into());
+            }
+            Op::Set(len) => {
+                let items = pop_n(&mut stack, len);
+                stack.push(items.into_iter().collect::<MalSet>().into());
+            }
+            Op::Special(i) => {
+                let value = eval::eval_value(&chunk.specials[i], frame)?;
+                stack.push(value);
+            }
+            Op::Call(nargs) => {
+                let args = pop_n(&mut stack, nargs);
+                let func = pop(&mut stack);
+                stack.push(eval::apply(func, args)?);
+            }
+            Op::TailCall(nargs) => {
+                let args = pop_n(&mut stack, nargs);
+                match pop(&mut stack) {
+                    Mal::Fn(MalFunc::Closure(closure)) => return Ok(Flow::TailCall(closure, args)),
+                    func => stack.push(eval::apply(func, args)?),
+                }
+            }
+            Op::Return => return Ok(Flow::Value(pop(&mut stack))),
+        }
+    }
+}
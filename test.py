@@ -17,6 +17,10 @@ STEPS = {
     "step4": "step4_if_fn_do",
 }
 
+# Tests of the features beyond the steps of mal, run with the last step.
+# Namespaces are required from the test directory.
+FEATURES = ["destructuring", "functions", "loop", "match", "lazy", "namespaces"]
+
 def get_step(step: str) -> str:
     step_name = STEPS.get(step)
     if step_name is None:
@@ -159,10 +163,10 @@ def print_results(passed, failed):
     
             
 
-def load_tests(step_name):
-    filepath = os.path.join(TEST_DIR, step_name+".mal")
+def load_tests(test_name):
+    filepath = os.path.join(TEST_DIR, test_name+".mal")
     if not os.path.exists(filepath):
-        raise FileNotFoundException("Could not find test file: {!r}".format(step_name+".mal"))
+        raise FileNotFoundError("Could not find test file: {!r}".format(test_name+".mal"))
     with open(filepath, "r") as f:
         text = f.read()
     
@@ -279,16 +283,26 @@ def print_tests(tests):
 
 def main(args=sys.argv[1:]):
     if not args:
-        return print("Usage: python3 test.py <step>")
+        return print("Usage: python3 test.py <step|feature> [--engine tree|vm]")
+    if len(args) == 3 and args[1] == "--engine":
+        # The binaries pick their engine up from the environment.
+        os.environ["MAL_ENGINE"] = args[2]
     from pprint import pprint
-    step_name = get_step(args[0])
-    tests = load_tests(step_name)
+    if args[0] in FEATURES:
+        step_name = STEPS["step4"]
+        tests = load_tests(args[0])
+        os.environ["MAL_PATH"] = TEST_DIR
+    else:
+        step_name = get_step(args[0])
+        tests = load_tests(step_name)
     #print_tests(tests)
     
     build_rust(step_name)
     cmd = rust_cmd(step_name)
     (passed, failed) = run_tests(tests, cmd)
     print_results(passed, failed)
+    if failed[TestType.Mandatory]:
+        sys.exit(1)
 
 
 if __name__ == '__main__':
@@ -0,0 +1,59 @@
+extern crate mal;
+
+use mal::{Interpreter, Mal};
+use mal::reader::read_str;
+
+fn eval(interpreter: &mut Interpreter, text: &str) -> Mal {
+    interpreter.eval(&read_str(text).unwrap()).unwrap()
+}
+
+/// The atom at the index of a list or vector.
+fn atom_at(value: &Mal, index: usize) -> Mal {
+    let item = match *value {
+        Mal::List(ref list) => list[index].clone(),
+        Mal::Arr(ref arr) => arr[index].clone(),
+        ref other => panic!("Not a sequence: {:?}", other),
+    };
+    match item {
+        Mal::Atom(atom) => atom.get(),
+        other => panic!("Not an atom: {:?}", other),
+    }
+}
+
+#[test]
+fn unreachable_cycles_are_collected() {
+    let mut interpreter = Interpreter::new();
+    eval(&mut interpreter, "(let* [a (atom nil)] (reset! a (fn* [] a)) nil)");
+    eval(&mut interpreter, "(let* [a (atom {})] (swap! a assoc :f (fn* [] a)) nil)");
+    eval(&mut interpreter, "(let* [a (atom nil)] (reset! a [1 [a]]) nil)");
+    let stats = interpreter.gc();
+    assert_eq!(stats.freed, 3);
+    assert_eq!(stats.atoms, 0);
+}
+
+#[test]
+fn reachable_atoms_are_kept() {
+    let mut interpreter = Interpreter::new();
+    eval(&mut interpreter, "(def! a (atom nil))");
+    eval(&mut interpreter, "(reset! a {:f (fn* [] a)})");
+    assert_eq!(interpreter.gc().freed, 0);
+    assert_eq!(eval(&mut interpreter, "(= (get @a :f) ((fn* [] (get @a :f))))"), Mal::from(true));
+}
+
+#[test]
+fn values_held_by_the_host_survive() {
+    let mut interpreter = Interpreter::new();
+    // The atom holds the same vector as the host.
+    let vector = eval(&mut interpreter, "(let* [a (atom nil) v [a (fn* [] a)]] (reset! a v) v)");
+    // The host holds a list that holds the map the atom holds.
+    let list = eval(&mut interpreter, "(let* [a (atom nil) m {:a a}] (reset! a m) (list a m))");
+    // The host holds a longer list made from the one the atom holds.
+    let long = eval(&mut interpreter,
+        "(let* [a (atom nil) l (concat [a] (take 100 (range)))] (reset! a l) (cons :x l))");
+    assert_eq!(interpreter.gc().freed, 0);
+    assert!(matches!(atom_at(&vector, 0), Mal::Arr(_)));
+    assert!(matches!(atom_at(&list, 0), Mal::Map(_)));
+    assert!(matches!(atom_at(&long, 1), Mal::List(_)));
+    drop((vector, list));
+    assert_eq!(interpreter.gc().freed, 2);
+}
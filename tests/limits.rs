@@ -0,0 +1,109 @@
+extern crate mal;
+
+use std::thread;
+use mal::{Interpreter, ErrorKind, Limits, Mal};
+use mal::reader::read_str;
+
+fn eval(interpreter: &mut Interpreter, text: &str) -> mal::Result<Mal> {
+    interpreter.eval(&read_str(text).unwrap())
+}
+
+/// Runs the test on a stack as big as the main thread's, which the
+/// default limits need.
+fn with_main_stack<F: FnOnce() + Send + 'static>(test: F) {
+    thread::Builder::new().stack_size(8 * 1024 * 1024).spawn(test).unwrap().join().unwrap();
+}
+
+#[test]
+fn lazy_sequences_run_again_after_exceeding_a_limit() {
+    let mut interpreter = Interpreter::new();
+    eval(&mut interpreter, "(def! s (lazy-seq (loop [i 0] (if (< i 2000) (recur (+ i 1)) (list i)))))")
+        .unwrap();
+    interpreter.set_limits(Limits { max_steps: Some(1000), ..Limits::default() });
+    for _ in 0..2 {
+        let err = eval(&mut interpreter, "(first s)").unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::LimitExceeded { .. }), "{}", err);
+    }
+    interpreter.set_limits(Limits::default());
+    assert_eq!(eval(&mut interpreter, "(first s)").unwrap(), Mal::from(2000.0));
+}
+
+#[test]
+fn lazy_sequences_fail_again_with_the_same_error() {
+    let mut interpreter = Interpreter::new();
+    eval(&mut interpreter, "(def! s (lazy-seq (+ 1 \"a\")))").unwrap();
+    for _ in 0..2 {
+        let err = eval(&mut interpreter, "(first s)").unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::TypeError { .. }), "{}", err);
+    }
+}
+
+const NEST: &str = "(def! nest (fn* [n acc] (if (= n 0) acc (nest (- n 1) [acc]))))";
+
+#[test]
+fn deeply_nested_values_can_be_dropped_and_compared() {
+    let mut interpreter = Interpreter::new();
+    eval(&mut interpreter, NEST).unwrap();
+    assert_eq!(eval(&mut interpreter, "(do (nest 50000 []) 1)").unwrap(), Mal::from(1.0));
+    eval(&mut interpreter, "(def! x (nest 20000 []))").unwrap();
+    assert_eq!(eval(&mut interpreter, "(= x x)").unwrap(), Mal::from(true));
+    assert_eq!(eval(&mut interpreter, "(= x (nest 20000 []))").unwrap(), Mal::from(true));
+    assert_eq!(eval(&mut interpreter, "(= x (nest 19999 []))").unwrap(), Mal::from(false));
+}
+
+#[test]
+fn deeply_nested_values_can_be_sorted_map_keys() {
+    with_main_stack(|| {
+        let mut interpreter = Interpreter::new();
+        eval(&mut interpreter, NEST).unwrap();
+        eval(&mut interpreter, "(def! x (nest 20000 []))").unwrap();
+        let entries = "(nest 20000 []) 1 (nest 19999 []) 2 [x] 3 [2] 4";
+        eval(&mut interpreter, &format!("(def! m (sorted-map {}))", entries)).unwrap();
+        assert_eq!(eval(&mut interpreter, "(count m)").unwrap(), Mal::from(4.0));
+        assert_eq!(eval(&mut interpreter, "(get m x)").unwrap(), Mal::from(1.0));
+        assert_eq!(eval(&mut interpreter, &format!("(= m (sorted-map {}))", entries)).unwrap(),
+            Mal::from(true));
+        assert_eq!(eval(&mut interpreter, "(= (sorted-map m 1) (sorted-map m 1))").unwrap(),
+            Mal::from(true));
+        let err = eval(&mut interpreter, "(pr-str m)").unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::LimitExceeded { .. }), "{}", err);
+    });
+}
+
+#[test]
+fn deeply_nested_maps_and_sets_can_be_dropped_and_compared() {
+    let mut interpreter = Interpreter::new();
+    for nested in &["{:a acc}", "{acc 1}", "#{acc}", "(atom acc)", "(fn* [] acc)"] {
+        eval(&mut interpreter, &format!(
+            "(def! nest (fn* [n acc] (if (= n 0) acc (nest (- n 1) {}))))", nested)).unwrap();
+        assert_eq!(eval(&mut interpreter, "(do (nest 50000 nil) 1)").unwrap(), Mal::from(1.0));
+    }
+    eval(&mut interpreter, "(def! nest (fn* [n acc] (if (= n 0) acc (nest (- n 1) #{acc}))))")
+        .unwrap();
+    eval(&mut interpreter, "(def! x (nest 20000 nil))").unwrap();
+    assert_eq!(eval(&mut interpreter, "(= x (nest 20000 nil))").unwrap(), Mal::from(true));
+    assert_eq!(eval(&mut interpreter, "(= x (nest 20000 1))").unwrap(), Mal::from(false));
+}
+
+#[test]
+fn printing_deeply_nested_values_exceeds_a_limit() {
+    with_main_stack(|| {
+        let mut interpreter = Interpreter::new();
+        eval(&mut interpreter, NEST).unwrap();
+        let err = eval(&mut interpreter, "(pr-str (nest 20000 []))").unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::LimitExceeded { .. }), "{}", err);
+    });
+}
+
+#[test]
+fn reading_deeply_nested_forms_exceeds_a_limit() {
+    with_main_stack(|| {
+        let text = "(".repeat(100000);
+        let err = read_str(&text).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::LimitExceeded { .. }), "{}", err);
+
+        let mut interpreter = Interpreter::new();
+        let err = eval(&mut interpreter, &format!("(read-string \"{}\")", text)).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::LimitExceeded { .. }), "{}", err);
+    });
+}
@@ -0,0 +1,55 @@
+extern crate mal;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use mal::{Interpreter, Mal, Symbol};
+use mal::reader::read_str;
+
+/// Writes a file for the test into a fresh directory.
+fn write_file(test: &str, text: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mal-modules-{}-{}", test, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.mal");
+    fs::write(&path, text).unwrap();
+    path
+}
+
+fn load_file(interpreter: &mut Interpreter, path: &Path) -> mal::Result<Mal> {
+    let form = format!("(load-file {:?})", path.to_str().unwrap());
+    interpreter.eval(&read_str(&form).unwrap())
+}
+
+#[test]
+fn load_file_restores_the_namespace() {
+    let path = write_file("restores", "(in-ns other) (def! x 1)");
+    let mut interpreter = Interpreter::new();
+    load_file(&mut interpreter, &path).unwrap();
+    assert_eq!(interpreter.env().ns(), Symbol::new("user"));
+    assert_eq!(interpreter.eval(&read_str("other/x").unwrap()).unwrap(), Mal::from(1.0));
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn load_file_restores_the_namespace_when_the_file_fails() {
+    let path = write_file("fails", "(in-ns other) (def! x 1) (undefined-thing)");
+    let mut interpreter = Interpreter::new();
+    assert!(load_file(&mut interpreter, &path).is_err());
+    assert_eq!(interpreter.env().ns(), Symbol::new("user"));
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn namespace_names_must_stay_on_the_search_path() {
+    let path = write_file("names", "(in-ns x) (def! y 1)");
+    let mut interpreter = Interpreter::new();
+    interpreter.env().modules().set_search_path(vec![path.parent().unwrap().join("sub")]);
+    let outside = path.with_extension("");
+    for name in &[outside.to_str().unwrap(), "a..b", ".a", "a.", "a.b/../c", "..", "a\\\\b"] {
+        let form = format!("(require {})", name);
+        let err = interpreter.eval(&read_str(&form).unwrap()).unwrap_err();
+        assert!(err.iter().any(|err| err.to_string().contains("is not a valid namespace name")),
+            "{}: {}", name, err);
+    }
+}
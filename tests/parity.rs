@@ -0,0 +1,115 @@
+extern crate mal;
+
+use std::thread;
+use mal::{Interpreter, Engine, ErrorKind, Limits};
+use mal::reader::read_all;
+
+/// Programs that must give the same results on both engines. Each runs in
+/// a fresh interpreter, and every top-level form is compared.
+const CORPUS: &[&str] = &[
+    // Destructuring
+    "(let* [[a b & more :as all] [1 2 3 4]] (list a b more all))",
+    "(let* [{:keys [a b] :or {b 5} :as m} {:a 1}] (list a b m))",
+    "(let* [{[x y] :point} {:point [3 4]} [p [q]] (list 1 [2])] (+ x y p q))",
+    "(def! [p q] [1 2]) (+ p q)",
+    "((fn* [[a b] {:keys [c]}] (list a b c)) [1 2] {:c 3})",
+    "(let* [{:keys [a]} (list :a 1)] a)",
+    "(let* [[a b]] a)",
+    "(let* [1 2] 1)",
+    // Match
+    "(match [1 [2 3]] [a [b c]] (list a b c) _ :no)",
+    "(match {:type :circle :r 2} {:type :square :side s} (* s s) {:type :circle :r r} (* 3 r r))",
+    "(match 0 n :when (< n 0) :neg n :when (> n 0) :pos _ :zero)",
+    "(match [1 2 3] [a & more :as all] (list more all))",
+    "(match 3 1 :one 2 :two)",
+    "(match 1 #{1} :set)",
+    "(match 1 1)",
+    // Loop and recur
+    "(loop [i 0 acc 0] (if (= i 10) acc (recur (+ i 1) (+ acc i))))",
+    "(loop [i 0] (if (< i 100000) (recur (+ i 1)) i))",
+    "(loop [[a & more] [1 2 3] acc []] (if a (recur more (conj acc (* a a))) acc))",
+    "(loop [i 0 fs []] (if (= i 3) (map (fn* [f] (f)) fs) (recur (+ i 1) (conj fs (fn* [] i)))))",
+    "((fn* [n & more] (if (= n 0) more (recur (- n 1) n))) 3)",
+    "(loop [[a] 1] a)",
+    "(recur 1)",
+    "(loop [i 0] (+ 1 (recur i)))",
+    "(loop [i 0 j 0] (recur 1))",
+    // Lazy sequences
+    "(def! nums (fn* [n] (lazy-seq (cons n (nums (+ n 1)))))) (take 5 (nums 0))",
+    "(def! calls (atom 0)) (def! s (lazy-seq (do (swap! calls + 1) (list 1 2)))) \
+     (first s) (count s) @calls",
+    "(take 3 (map (fn* [x] (* x x)) (filter (fn* [x] (> x 2)) (range))))",
+    "(first (drop 100000 (range)))",
+    "(first (lazy-seq (first 1)))",
+    "(def! bad (lazy-seq (+ 1 (first 1)))) (count bad) (count bad)",
+    // Multi-arity
+    "(def! f (fn* ([] 0) ([a] a) ([a b] (+ a b)) ([a b & more] (list a b more)))) \
+     (f) (f 1) (f 1 2) (f 1 2 3 4)",
+    "(def! g (fn* ([a] :fixed) ([a & more] :variadic))) (g 1) (g 1 2)",
+    "(def! g (fn* ([a & more] :variadic) ([a] :fixed))) (g 1) (g 1 2)",
+    "(def! h (fn* ([a] (h a 10)) ([a b] (* a b)))) (h 5)",
+    "((fn* ([a] a) ([a b c] a)) 1 2)",
+    "(fn* ([a] 1) ([b] 2))",
+    "(fn* ([& a] 1) ([b & c] 2))",
+    // Closures and errors
+    "(def! adder (fn* [n] (fn* [x] (+ x n)))) ((adder 2) 3)",
+    "(let* [a 1 f (fn* [] a)] (let* [a 2] (f)))",
+    "(undefined-thing 1)",
+    "(+ 1 \"a\")",
+    "(1 2)",
+];
+
+fn kind_name(kind: &ErrorKind) -> &'static str {
+    match *kind {
+        ErrorKind::Msg(_) => "Msg",
+        ErrorKind::Lexer { .. } => "Lexer",
+        ErrorKind::Reader { .. } => "Reader",
+        ErrorKind::TypeError { .. } => "TypeError",
+        ErrorKind::LimitExceeded { .. } => "LimitExceeded",
+        ErrorKind::Interrupted(_) => "Interrupted",
+        ErrorKind::SandboxDenied(_) => "SandboxDenied",
+    }
+}
+
+/// The printed value of each form, or the kind and message of its error.
+fn run(engine: Engine, limits: Limits, program: &str) -> Vec<String> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_engine(engine);
+    interpreter.set_limits(limits);
+    let forms = read_all(program).unwrap();
+    forms.iter()
+        .map(|form| {
+            match interpreter.eval(form).and_then(|value| interpreter.print(&value, true)) {
+                Ok(printed) => printed,
+                Err(err) => format!("error {}: {}", kind_name(err.kind()), err),
+            }
+        })
+        .collect()
+}
+
+fn assert_parity(limits: Limits, programs: &[&str]) {
+    for program in programs {
+        let tree = run(Engine::Tree, limits, program);
+        let vm = run(Engine::Vm, limits, program);
+        assert_eq!(tree, vm, "the engines disagree on {}", program);
+    }
+}
+
+#[test]
+fn engines_agree_on_the_corpus() {
+    assert_parity(Limits::default(), CORPUS);
+}
+
+#[test]
+fn engines_agree_on_exceeded_limits() {
+    // The default stack limit needs a stack as big as the main thread's.
+    let child = thread::Builder::new().stack_size(8 * 1024 * 1024).spawn(|| {
+        let limits = Limits { max_steps: Some(1000), max_depth: Some(50), ..Limits::default() };
+        assert_parity(limits, &[
+            "(loop [i 0] (recur (+ i 1)))",
+            "(def! down (fn* [n] (if (= n 0) 0 (+ 1 (down (- n 1)))))) (down 10) (down 100)",
+            "(def! s (lazy-seq (loop [i 0] (recur (+ i 1))))) (first s)",
+        ]);
+    }).unwrap();
+    child.join().unwrap();
+}
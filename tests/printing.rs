@@ -0,0 +1,43 @@
+extern crate mal;
+
+use std::time::Duration;
+use mal::{Interpreter, ErrorKind, Mal};
+use mal::reader::read_str;
+
+fn eval(interpreter: &mut Interpreter, text: &str) -> Mal {
+    interpreter.eval(&read_str(text).unwrap()).unwrap()
+}
+
+#[test]
+fn print_length_cuts_lazy_sequences_short() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_print_length(Some(3));
+    let range = eval(&mut interpreter, "(range)");
+    assert_eq!(interpreter.print(&range, true).unwrap(), "(0 1 2 ...)");
+    let short = eval(&mut interpreter, "(take 3 (range))");
+    assert_eq!(interpreter.print(&short, true).unwrap(), "(0 1 2)");
+    assert_eq!(eval(&mut interpreter, "(pr-str (map (fn* [x] (* x x)) (range)))"),
+        Mal::from("(0 1 4 ...)".to_string()));
+}
+
+#[test]
+fn printing_an_infinite_sequence_times_out() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_timeout(Some(Duration::from_millis(50)));
+    let range = eval(&mut interpreter, "(range)");
+    let err = interpreter.print(&range, true).unwrap_err();
+    assert!(matches!(*err.kind(), ErrorKind::Interrupted(_)), "{}", err);
+}
+
+#[test]
+fn printing_an_infinite_sequence_can_be_cancelled() {
+    let mut interpreter = Interpreter::new();
+    let token = interpreter.cancel_token();
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        token.cancel();
+    });
+    let err = interpreter.eval(&read_str("(pr-str (range))").unwrap()).unwrap_err();
+    assert!(matches!(*err.kind(), ErrorKind::Interrupted(_)), "{}", err);
+    canceller.join().unwrap();
+}
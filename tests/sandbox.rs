@@ -0,0 +1,170 @@
+extern crate mal;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use mal::{Interpreter, ErrorKind, Mal, Sandbox};
+use mal::reader::read_str;
+
+/// Makes a fresh directory for the test, with `allowed` and `outside`
+/// directories in it, and a file in each.
+fn make_dirs(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mal-sandbox-{}-{}", test, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for sub in &["allowed", "outside"] {
+        fs::create_dir_all(dir.join(sub)).unwrap();
+        fs::write(dir.join(sub).join("file.bin"), b"data").unwrap();
+    }
+    dir
+}
+
+/// An interpreter that may only read and write in `allowed`.
+fn sandboxed(dir: &Path) -> Interpreter {
+    let allowed = vec![dir.join("allowed")];
+    let mut interpreter = Interpreter::new();
+    interpreter.set_sandbox(Sandbox {
+        read_dirs: Some(allowed.clone()),
+        write_dirs: Some(allowed),
+        ..Sandbox::strict()
+    });
+    interpreter
+}
+
+fn slurp(interpreter: &mut Interpreter, path: &Path) -> mal::Result<Mal> {
+    let form = format!("(slurp-bytes {:?})", path.to_str().unwrap());
+    interpreter.eval(&read_str(&form).unwrap())
+}
+
+fn spit(interpreter: &mut Interpreter, path: &Path) -> mal::Result<Mal> {
+    let form = format!("(spit-bytes {:?} (string->bytes \"new\"))", path.to_str().unwrap());
+    interpreter.eval(&read_str(&form).unwrap())
+}
+
+fn assert_denied(res: mal::Result<Mal>) {
+    match res {
+        Err(ref err) if matches!(*err.kind(), ErrorKind::SandboxDenied(_)) => {}
+        other => panic!("expected the sandbox to deny it, got {:?}", other),
+    }
+}
+
+#[test]
+fn files_are_read_only_in_the_allowed_directories() {
+    let dir = make_dirs("read");
+    let mut interpreter = sandboxed(&dir);
+    assert_eq!(slurp(&mut interpreter, &dir.join("allowed/file.bin")).unwrap(),
+        Mal::from(b"data".to_vec()));
+    assert_denied(slurp(&mut interpreter, &dir.join("outside/file.bin")));
+    assert_denied(slurp(&mut interpreter, &dir.join("allowed/../outside/file.bin")));
+    let form = format!("(load-file {:?})", dir.join("outside/file.bin").to_str().unwrap());
+    assert_denied(interpreter.eval(&read_str(&form).unwrap()));
+}
+
+#[test]
+fn files_are_written_only_in_the_allowed_directories() {
+    let dir = make_dirs("write");
+    let mut interpreter = sandboxed(&dir);
+    spit(&mut interpreter, &dir.join("allowed/file.bin")).unwrap();
+    spit(&mut interpreter, &dir.join("allowed/new.bin")).unwrap();
+    assert_eq!(fs::read(dir.join("allowed/new.bin")).unwrap(), b"new");
+    assert_denied(spit(&mut interpreter, &dir.join("outside/file.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("outside/new.bin")));
+    assert_eq!(fs::read(dir.join("outside/file.bin")).unwrap(), b"data");
+    assert!(! dir.join("outside/new.bin").exists());
+}
+
+#[test]
+fn new_files_under_parent_directories_are_resolved() {
+    let dir = make_dirs("parent");
+    let mut interpreter = sandboxed(&dir);
+    fs::create_dir_all(dir.join("allowed/sub")).unwrap();
+    spit(&mut interpreter, &dir.join("allowed/sub/../new.bin")).unwrap();
+    assert!(dir.join("allowed/new.bin").exists());
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/../new.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/../outside/new.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/missing/../../new.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/..")));
+    assert!(! dir.join("new.bin").exists());
+    assert!(! dir.join("outside/new.bin").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn links_do_not_lead_out_of_the_allowed_directories() {
+    use std::os::unix::fs::symlink;
+    let dir = make_dirs("links");
+    let mut interpreter = sandboxed(&dir);
+    symlink(dir.join("outside/file.bin"), dir.join("allowed/file-link.bin")).unwrap();
+    symlink(dir.join("outside"), dir.join("allowed/dir-link")).unwrap();
+    symlink(dir.join("outside/missing.bin"), dir.join("allowed/dangling.bin")).unwrap();
+    assert_denied(slurp(&mut interpreter, &dir.join("allowed/file-link.bin")));
+    assert_denied(slurp(&mut interpreter, &dir.join("allowed/dir-link/file.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/file-link.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/dir-link/new.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/dangling.bin")));
+    assert_eq!(fs::read(dir.join("outside/file.bin")).unwrap(), b"data");
+    assert!(! dir.join("outside/new.bin").exists());
+    assert!(! dir.join("outside/missing.bin").exists());
+}
+
+#[test]
+fn strict_sandboxes_allow_nothing_and_the_default_everything() {
+    let dir = make_dirs("strict");
+    let mut interpreter = Interpreter::new();
+    assert_eq!(*interpreter.sandbox(), Sandbox::unrestricted());
+    spit(&mut interpreter, &dir.join("outside/new.bin")).unwrap();
+    interpreter.set_sandbox(Sandbox::strict());
+    assert_denied(slurp(&mut interpreter, &dir.join("allowed/file.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/file.bin")));
+    assert_denied(interpreter.eval(&read_str("(sh \"echo\")").unwrap()));
+    assert_denied(interpreter.eval(&read_str("(getenv \"HOME\")").unwrap()));
+}
+
+#[test]
+fn required_files_are_checked_like_any_other() {
+    let dir = make_dirs("require");
+    fs::write(dir.join("outside/lib.mal"), "(in-ns lib) (def! x 1)").unwrap();
+    let mut interpreter = sandboxed(&dir);
+    interpreter.env().modules().set_search_path(vec![dir.join("outside")]);
+    let err = interpreter.eval(&read_str("(require lib)").unwrap()).unwrap_err();
+    assert!(err.iter().any(|err| err.to_string().starts_with("Not allowed by the sandbox")),
+        "{}", err);
+}
+
+#[test]
+fn processes_are_only_started_if_allowed() {
+    let mut interpreter = Interpreter::new();
+    let form = read_str("(get (sh \"echo\" \"hi\") :out)").unwrap();
+    assert_eq!(interpreter.eval(&form).unwrap(), Mal::from("hi\n".to_string()));
+    interpreter.set_sandbox(Sandbox { allow_processes: false, ..Sandbox::unrestricted() });
+    assert_denied(interpreter.eval(&form));
+    interpreter.set_sandbox(Sandbox { allow_processes: true, ..Sandbox::strict() });
+    assert_eq!(interpreter.eval(&form).unwrap(), Mal::from("hi\n".to_string()));
+}
+
+#[test]
+fn environment_variables_are_only_read_if_allowed() {
+    env::set_var("MAL_SANDBOX_TEST", "value");
+    let mut interpreter = Interpreter::new();
+    let form = read_str("(getenv \"MAL_SANDBOX_TEST\")").unwrap();
+    assert_eq!(interpreter.eval(&form).unwrap(), Mal::from("value".to_string()));
+    interpreter.set_sandbox(Sandbox { allow_env: false, ..Sandbox::unrestricted() });
+    assert_denied(interpreter.eval(&form));
+    interpreter.set_sandbox(Sandbox { allow_env: true, ..Sandbox::strict() });
+    assert_eq!(interpreter.eval(&form).unwrap(), Mal::from("value".to_string()));
+}
+
+#[cfg(feature = "repl")]
+#[test]
+fn the_sandbox_flag_only_allows_reading_here_and_on_the_search_path() {
+    let dir = make_dirs("flag");
+    let mut interpreter = Interpreter::new();
+    interpreter.env().modules().set_search_path(vec![dir.join("allowed")]);
+    let mut args = vec!["(+ 1 2)".to_string(), "--sandbox".to_string()];
+    mal::repl::configure(&mut interpreter, &mut args);
+    assert_eq!(args, ["(+ 1 2)"]);
+    slurp(&mut interpreter, &dir.join("allowed/file.bin")).unwrap();
+    assert_denied(slurp(&mut interpreter, &dir.join("outside/file.bin")));
+    assert_denied(spit(&mut interpreter, &dir.join("allowed/new.bin")));
+    assert_denied(interpreter.eval(&read_str("(getenv \"HOME\")").unwrap()));
+}